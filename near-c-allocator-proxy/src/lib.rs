@@ -0,0 +1,456 @@
+//! `LD_PRELOAD` library adding the header of `near-rust-allocator-proxy` to allocations made with
+//! `malloc` and friends, so that `rust-memory-analyzer` finds C and C++ allocations too.
+//!
+//! Allocations go through a `ProxyAllocator` on top of the glibc allocator, which is called through
+//! its internal `__libc_*` names. The header is right before the returned pointer, except for
+//! alignments above the header size, where it's at the start of the block and the 32 bytes before
+//! the returned pointer hold `[0, 0, ALIGNED_MARKER, alignment]` instead.
+//!
+//! Anonymous mappings made with `mmap` and unmapped with `munmap` are recorded in the mmap log of
+//! the proxy, see `ProxyAllocator::enable_mmap_log`.
+// The exported functions have the contracts of the C functions they replace.
+#![allow(clippy::missing_safety_doc)]
+
+use near_rust_allocator_proxy::{record_mmap, record_munmap, AllocHeader, ProxyAllocator};
+use std::alloc::{GlobalAlloc, Layout};
+use std::mem::size_of;
+use std::os::raw::{c_int, c_void};
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+extern "C" {
+    fn __libc_malloc(size: usize) -> *mut c_void;
+    fn __libc_memalign(alignment: usize, size: usize) -> *mut c_void;
+    fn __libc_realloc(ptr: *mut c_void, size: usize) -> *mut c_void;
+    fn __libc_free(ptr: *mut c_void);
+}
+
+/// The glibc allocator.
+struct Libc;
+
+unsafe impl GlobalAlloc for Libc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = if layout.align() <= MIN_ALIGN {
+            __libc_malloc(layout.size())
+        } else {
+            __libc_memalign(layout.align(), layout.size())
+        };
+        ptr.cast()
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        __libc_free(ptr.cast());
+    }
+}
+
+static ALLOC: ProxyAllocator<Libc> = ProxyAllocator::new(Libc);
+
+/// Alignment guaranteed by `malloc`.
+const MIN_ALIGN: usize = 16;
+const HEADER_SIZE: usize = size_of::<AllocHeader>();
+/// Precedes the alignment of blocks, whose header isn't right before the returned pointer.
+const ALIGNED_MARKER: usize = 0x616c_6967_6e65_6421;
+/// Smallest page size of supported platforms, bytes before a pointer at least this far from a
+/// multiple of it are on the same page.
+const MIN_PAGE_SIZE: usize = 4096;
+/// Largest size of a layout.
+const MAX_SIZE: usize = isize::MAX as usize;
+/// Bytes the proxy adds to a request besides up to three times its alignment: the header and the
+/// canaries.
+const MAX_PADDING: usize = HEADER_SIZE + 2 * size_of::<u64>();
+
+/// Enables stack traces unless `NEAR_ALLOCATOR_PROXY_STACK_TRACE=0`, the mmap log unless
+/// `NEAR_ALLOCATOR_PROXY_MMAP_LOG=0` and reporting of double frees. Symbol resolution and the
+/// quarantine are only enabled with `NEAR_ALLOCATOR_PROXY_RESOLVE_SYMBOLS=1` and
+/// `NEAR_ALLOCATOR_PROXY_QUARANTINE=1`.
+#[used]
+#[link_section = ".init_array"]
+static INIT: extern "C" fn() = init;
+
+extern "C" fn init() {
+    let var = |name: &str| std::env::var_os(name);
+    let enabled = |name: &str| var(name).map_or(true, |value| value != "0");
+    let opted_in = |name: &str| var(name).map_or(false, |value| value == "1");
+    ALLOC.enable_stack_trace(enabled("NEAR_ALLOCATOR_PROXY_STACK_TRACE"));
+    // Resolving symbols inside `malloc` takes loader locks, which can deadlock with `dlopen`.
+    ALLOC.enable_symbol_resolution(opted_in("NEAR_ALLOCATOR_PROXY_RESOLVE_SYMBOLS"));
+    ALLOC.enable_mmap_log(enabled("NEAR_ALLOCATOR_PROXY_MMAP_LOG"));
+    ALLOC.enable_hardened_dealloc(true);
+    // glibc overwrites the start of freed blocks, where the header is, so double frees are only
+    // detected while blocks are in quarantine.
+    ALLOC.enable_quarantine(opted_in("NEAR_ALLOCATOR_PROXY_QUARANTINE"));
+}
+
+/// Allocates `size` bytes aligned to `align`, which has to be a power of two.
+unsafe fn alloc(size: usize, align: usize) -> *mut c_void {
+    let align = align.max(MIN_ALIGN);
+    // Larger requests fail without calling the inner allocator, so that adding the header and
+    // padding the block can't overflow.
+    let padded = align.checked_mul(3).and_then(|padding| padding.checked_add(MAX_PADDING));
+    if padded.and_then(|padding| padding.checked_add(size)).map_or(true, |size| size > MAX_SIZE) {
+        return null_mut();
+    }
+    let layout = match Layout::from_size_align(size, align) {
+        Ok(layout) => layout,
+        Err(_) => return null_mut(),
+    };
+    let ptr = ALLOC.alloc(layout);
+    if !ptr.is_null() && layout.align() > HEADER_SIZE {
+        ptr.sub(HEADER_SIZE).cast::<[usize; 4]>().write([0, 0, ALIGNED_MARKER, layout.align()]);
+    }
+    ptr.cast()
+}
+
+/// Layout `ptr` was allocated with by `alloc`, `None` if it was allocated by someone else. Blocks
+/// freed before are included, so that freeing them again is reported.
+unsafe fn layout_of(ptr: *mut c_void) -> Option<Layout> {
+    let before = read_before(ptr)?;
+    let header = &*before.as_ptr().cast::<AllocHeader>();
+    if header.valid() {
+        return Some(Layout::from_size_align_unchecked(header.size(), MIN_ALIGN));
+    }
+    let [_, _, marker, align] = before;
+    if marker != ALIGNED_MARKER || !align.is_power_of_two() || align <= HEADER_SIZE {
+        return None;
+    }
+    // The marker is only written by `alloc`, so the block starts `align` bytes before `ptr`.
+    let header = &*ptr.cast::<u8>().sub(align).cast::<AllocHeader>();
+    header.valid().then(|| Layout::from_size_align_unchecked(header.size(), align))
+}
+
+/// The `HEADER_SIZE` bytes before `ptr`, `None` if they can't be read. Pointers of others can be
+/// at the start of a mapping, e.g. of blocks glibc allocates with `mmap`, so bytes on the previous
+/// page are copied with `process_vm_readv`, which fails instead of faulting.
+unsafe fn read_before(ptr: *mut c_void) -> Option<[usize; 4]> {
+    let before = (ptr as usize).checked_sub(HEADER_SIZE)?;
+    if ptr as usize % MIN_PAGE_SIZE >= HEADER_SIZE {
+        return Some((before as *const [usize; 4]).read());
+    }
+    let mut words = [0usize; 4];
+    let local = libc::iovec { iov_base: words.as_mut_ptr().cast(), iov_len: HEADER_SIZE };
+    let remote = libc::iovec { iov_base: before as *mut c_void, iov_len: HEADER_SIZE };
+    let read = libc::process_vm_readv(libc::getpid(), &local, 1, &remote, 1, 0);
+    (read == HEADER_SIZE as isize).then_some(words)
+}
+
+fn set_errno(errno: c_int) {
+    unsafe { *libc::__errno_location() = errno };
+}
+
+/// Returns `ptr` after setting `errno` to `ENOMEM` if it's null.
+fn check_oom(ptr: *mut c_void) -> *mut c_void {
+    if ptr.is_null() {
+        set_errno(libc::ENOMEM);
+    }
+    ptr
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn malloc(size: usize) -> *mut c_void {
+    check_oom(alloc(size, MIN_ALIGN))
+}
+
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn calloc(nmemb: usize, size: usize) -> *mut c_void {
+    let size = match nmemb.checked_mul(size) {
+        Some(size) => size,
+        None => return check_oom(null_mut()),
+    };
+    let ptr = check_oom(alloc(size, MIN_ALIGN));
+    if !ptr.is_null() {
+        ptr.write_bytes(0, size);
+    }
+    ptr
+}
+
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn free(ptr: *mut c_void) {
+    if ptr.is_null() {
+        return;
+    }
+    match layout_of(ptr) {
+        Some(layout) => ALLOC.dealloc(ptr.cast(), layout),
+        None => __libc_free(ptr),
+    }
+}
+
+/// Doesn't keep alignments above the one of `malloc`, just like glibc.
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: usize) -> *mut c_void {
+    if ptr.is_null() {
+        return malloc(size);
+    }
+    if size == 0 {
+        free(ptr);
+        return null_mut();
+    }
+    let layout = match layout_of(ptr) {
+        Some(layout) => layout,
+        None => return __libc_realloc(ptr, size),
+    };
+    let new = check_oom(alloc(size, MIN_ALIGN));
+    if !new.is_null() {
+        new.copy_from_nonoverlapping(ptr, layout.size().min(size));
+        ALLOC.dealloc(ptr.cast(), layout);
+    }
+    new
+}
+
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn posix_memalign(
+    memptr: *mut *mut c_void,
+    alignment: usize,
+    size: usize,
+) -> c_int {
+    if !alignment.is_power_of_two() || alignment % size_of::<*mut c_void>() != 0 {
+        return libc::EINVAL;
+    }
+    let ptr = alloc(size, alignment);
+    if ptr.is_null() {
+        return libc::ENOMEM;
+    }
+    *memptr = ptr;
+    0
+}
+
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn aligned_alloc(alignment: usize, size: usize) -> *mut c_void {
+    if !alignment.is_power_of_two() {
+        set_errno(libc::EINVAL);
+        return null_mut();
+    }
+    check_oom(alloc(size, alignment))
+}
+
+/// Rounds `alignment` up to a power of two, like glibc.
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn memalign(alignment: usize, size: usize) -> *mut c_void {
+    match alignment.checked_next_power_of_two() {
+        Some(alignment) => check_oom(alloc(size, alignment)),
+        None => {
+            set_errno(libc::EINVAL);
+            null_mut()
+        }
+    }
+}
+
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn valloc(size: usize) -> *mut c_void {
+    check_oom(alloc(size, page_size()))
+}
+
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn pvalloc(size: usize) -> *mut c_void {
+    let page_size = page_size();
+    match size.max(1).checked_add(page_size - 1) {
+        Some(size) => check_oom(alloc(size & !(page_size - 1), page_size)),
+        None => check_oom(null_mut()),
+    }
+}
+
+/// Returns the requested size, the allocator may have reserved more.
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn malloc_usable_size(ptr: *mut c_void) -> usize {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    if ptr.is_null() {
+        return 0;
+    }
+    if let Some(layout) = layout_of(ptr) {
+        return layout.size();
+    }
+    let mut next = NEXT.load(Ordering::Relaxed);
+    if next == 0 {
+        next = libc::dlsym(libc::RTLD_NEXT, b"malloc_usable_size\0".as_ptr().cast()) as usize;
+        NEXT.store(next, Ordering::Relaxed);
+    }
+    if next == 0 {
+        return 0;
+    }
+    let next: unsafe extern "C" fn(*mut c_void) -> usize = std::mem::transmute(next);
+    next(ptr)
+}
+
+/// Calls the `mmap` syscall directly, glibc's `mmap` does nothing else on 64 bit platforms.
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn mmap(
+    addr: *mut c_void,
+    len: usize,
+    prot: c_int,
+    flags: c_int,
+    fd: c_int,
+    offset: libc::off_t,
+) -> *mut c_void {
+    let ptr = libc::syscall(
+        libc::SYS_mmap,
+        addr,
+        len,
+        prot as libc::c_long,
+        flags as libc::c_long,
+        fd as libc::c_long,
+        offset as libc::c_long,
+    ) as *mut c_void;
+    if ptr != libc::MAP_FAILED && flags & libc::MAP_ANONYMOUS != 0 {
+        record_mmap(ptr, len);
+    }
+    ptr
+}
+
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn mmap64(
+    addr: *mut c_void,
+    len: usize,
+    prot: c_int,
+    flags: c_int,
+    fd: c_int,
+    offset: libc::off64_t,
+) -> *mut c_void {
+    mmap(addr, len, prot, flags, fd, offset)
+}
+
+#[cfg_attr(not(test), no_mangle)]
+pub unsafe extern "C" fn munmap(addr: *mut c_void, len: usize) -> c_int {
+    let res = libc::syscall(libc::SYS_munmap, addr, len) as c_int;
+    if res == 0 {
+        record_munmap(addr, len);
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        __libc_malloc, aligned_alloc, calloc, free, layout_of, malloc, malloc_usable_size,
+        memalign, mmap, munmap, page_size, posix_memalign, pvalloc, realloc, valloc, HEADER_SIZE,
+    };
+    use near_rust_allocator_proxy::{total_alloc_stats, AllocHeader};
+    use std::os::raw::c_void;
+    use std::ptr::null_mut;
+
+    #[test]
+    fn test_malloc() {
+        unsafe {
+            let ptr = malloc(100);
+            let header = &*ptr.cast::<u8>().sub(HEADER_SIZE).cast::<AllocHeader>();
+            assert!(header.is_allocated());
+            assert_eq!(header.size(), 100);
+            assert_eq!(malloc_usable_size(ptr), 100);
+
+            ptr.cast::<u8>().write_bytes(7, 100);
+            let ptr = realloc(ptr, 1000);
+            assert_eq!(std::slice::from_raw_parts(ptr.cast::<u8>(), 100), &[7; 100]);
+            assert_eq!(malloc_usable_size(ptr), 1000);
+            assert!(realloc(ptr, 0).is_null());
+        }
+    }
+
+    #[test]
+    fn test_calloc() {
+        unsafe {
+            let before = total_alloc_stats();
+            let ptr = calloc(10, 100);
+            assert!(total_alloc_stats().allocated_bytes >= before.allocated_bytes + 1000);
+            assert_eq!(std::slice::from_raw_parts(ptr.cast::<u8>(), 1000), &[0; 1000]);
+            free(ptr);
+            assert!(calloc(usize::MAX, 2).is_null());
+        }
+    }
+
+    #[test]
+    fn test_aligned() {
+        let page_size = page_size();
+        for alignment in [8, 16, 32, 64, 4096] {
+            unsafe {
+                let mut ptr = null_mut();
+                assert_eq!(posix_memalign(&mut ptr, alignment, 100), 0);
+                assert_eq!(ptr as usize % alignment, 0);
+                assert_eq!(layout_of(ptr).map(|layout| layout.size()), Some(100));
+                free(ptr);
+
+                let ptr = aligned_alloc(alignment, 100);
+                assert_eq!(ptr as usize % alignment, 0);
+                free(ptr);
+            }
+        }
+        unsafe {
+            let ptr = memalign(48, 100);
+            assert_eq!(ptr as usize % 64, 0);
+            free(ptr);
+            let ptr = valloc(100);
+            assert_eq!((ptr as usize % page_size, malloc_usable_size(ptr)), (0, 100));
+            free(ptr);
+            let ptr = pvalloc(100);
+            assert_eq!((ptr as usize % page_size, malloc_usable_size(ptr)), (0, page_size));
+            free(ptr);
+        }
+    }
+
+    #[test]
+    fn test_huge_alignment() {
+        unsafe {
+            let mut ptr = null_mut();
+            assert_eq!(posix_memalign(&mut ptr, 1 << 62, 1 << 62), libc::ENOMEM);
+            assert_eq!(posix_memalign(&mut ptr, 1 << 62, 1), libc::ENOMEM);
+            assert!(ptr.is_null());
+            assert!(aligned_alloc(1 << 62, 1 << 62).is_null());
+            assert!(aligned_alloc(1 << 63, 1).is_null());
+            assert!(memalign(usize::MAX / 3, 1).is_null());
+            assert!(malloc(usize::MAX - HEADER_SIZE).is_null());
+        }
+    }
+
+    #[test]
+    fn test_foreign_pointer() {
+        unsafe {
+            let ptr: *mut c_void = __libc_malloc(100);
+            assert_eq!(layout_of(ptr), None);
+            assert!(malloc_usable_size(ptr) >= 100);
+            let ptr = realloc(ptr, 1000);
+            assert_eq!(layout_of(ptr), None);
+            free(ptr);
+        }
+    }
+
+    #[test]
+    fn test_pointer_at_page_start() {
+        unsafe {
+            let page_size = page_size();
+            let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+            let prot = libc::PROT_READ | libc::PROT_WRITE;
+            let pages = mmap(null_mut(), 2 * page_size, prot, flags, -1, 0).cast::<u8>();
+            assert_ne!(pages.cast(), libc::MAP_FAILED);
+            // A header crossing the page boundary is found.
+            let ptr = malloc(100);
+            let block = pages.add(page_size);
+            block.sub(16).copy_from_nonoverlapping(ptr.cast::<u8>().sub(HEADER_SIZE), HEADER_SIZE);
+            assert_eq!(layout_of(block.add(16).cast()).map(|layout| layout.size()), Some(100));
+            free(ptr);
+
+            // Like a block glibc allocated with `mmap`, the page before isn't mapped.
+            assert_eq!(munmap(pages.cast(), page_size), 0);
+            assert_eq!(layout_of(block.add(16).cast()), None);
+            assert_eq!(layout_of(block.cast()), None);
+            assert_eq!(munmap(block.cast(), page_size), 0);
+        }
+    }
+
+    #[test]
+    fn test_mmap() {
+        unsafe {
+            let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+            let ptr = mmap(null_mut(), 1 << 20, libc::PROT_READ | libc::PROT_WRITE, flags, -1, 0);
+            assert_ne!(ptr, libc::MAP_FAILED);
+            ptr.cast::<u8>().write_bytes(1, 1 << 20);
+            assert_eq!(munmap(ptr, 1 << 20), 0);
+
+            let ptr = mmap(null_mut(), 4096, libc::PROT_READ, flags, -1, 0);
+            assert_ne!(ptr, libc::MAP_FAILED);
+            assert_eq!(munmap(ptr.cast::<u8>().add(1).cast(), 4096), -1);
+            assert_eq!(*libc::__errno_location(), libc::EINVAL);
+            assert_eq!(munmap(ptr, 4096), 0);
+        }
+    }
+}
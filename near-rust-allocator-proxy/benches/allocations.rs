@@ -20,6 +20,23 @@ fn alloc_1024(c: &mut Criterion) {
     });
 }
 
+/// Only measures frame pointer walks with `RUSTFLAGS="-C force-frame-pointers=yes"`, otherwise
+/// walks stop right away and fall back to `backtrace`.
+fn alloc_1024_frame_pointers(c: &mut Criterion) {
+    let format = tracing_subscriber::fmt::format()
+        .with_level(true) // don't include levels in formatted output
+        .with_target(true) // don't include targets
+        .without_time();
+    tracing_subscriber::fmt().event_format(format).finish().try_init().ok();
+    ALLOC.set_verbose(false).enable_stack_trace(true).enable_frame_pointers(true);
+    c.bench_function("alloc_1024_frame_pointers", |b| {
+        b.iter(|| {
+            black_box(Vec::<u8>::with_capacity(1024));
+        })
+    });
+    ALLOC.enable_frame_pointers(false);
+}
+
 fn alloc_32(c: &mut Criterion) {
     let format = tracing_subscriber::fmt::format()
         .with_level(true) // don't include levels in formatted output
@@ -34,7 +51,7 @@ fn alloc_32(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, alloc_32, alloc_1024);
+criterion_group!(benches, alloc_32, alloc_1024, alloc_1024_frame_pointers);
 criterion_main!(benches);
 /*
 alloc_32                time:   [38.494 ns 38.525 ns 38.557 ns]
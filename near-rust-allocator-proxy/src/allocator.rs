@@ -1,9 +1,14 @@
+use crate::canary::{self, CANARIES};
+use crate::hardened::{self, Quarantine, HARDENED, QUARANTINE, QUARANTINE_MAX_BLOCK};
+use crate::mmap_log::{self, MMAP_LOG};
+use crate::{histogram, live_set, scope, skip_cache, AllocStats};
 use backtrace::Backtrace;
 use std::alloc::{GlobalAlloc, Layout};
 use std::cell::Cell;
 use std::os::raw::c_void;
 use std::ptr::null_mut;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
 use tracing::info;
 
 const MEBIBYTE: usize = 1 << 20;
@@ -13,25 +18,64 @@ const SKIP_ADDR_ABOVE: *mut c_void = 0x7000_0000_0000 as *mut c_void;
 pub(crate) static REPORT_USAGE_INTERVAL: AtomicUsize = AtomicUsize::new(usize::MAX);
 /// Should be a configurable option.
 pub(crate) static ENABLE_STACK_TRACE: AtomicBool = AtomicBool::new(false);
+/// Walk the stack using frame pointers instead of `backtrace`.
+pub(crate) static USE_FRAME_POINTERS: AtomicBool = AtomicBool::new(false);
+/// Resolve symbols of new addresses to decide whether they are skipped.
+pub(crate) static SYMBOL_RESOLUTION: AtomicBool = AtomicBool::new(true);
+/// Leave symbolization of new addresses to the background resolver thread.
+pub(crate) static ASYNC_SYMBOL_RESOLUTION: AtomicBool = AtomicBool::new(false);
+/// Record live sampled allocations in the registry.
+pub(crate) static LIVE_TRACKING: AtomicBool = AtomicBool::new(false);
+
+/// Allocations smaller than this are sampled.
+const SMALL_BLOCK_SIZE: usize = 1000;
+/// Small allocations get a stack trace `SMALL_BLOCK_SAMPLES` out of `SMALL_BLOCK_PERIOD` times.
+const SMALL_BLOCK_SAMPLES: u64 = 10;
+const SMALL_BLOCK_PERIOD: u64 = 1024;
 pub(crate) static VERBOSE: AtomicBool = AtomicBool::new(false);
 
-const COUNTERS_SIZE: usize = 16384;
-static MEM_SIZE: [AtomicUsize; COUNTERS_SIZE] = unsafe {
+pub(crate) const COUNTERS_SIZE: usize = 16384;
+pub(crate) static MEM_SIZE: [AtomicUsize; COUNTERS_SIZE] = unsafe {
     // SAFETY: Rust [guarantees](https://doc.rust-lang.org/stable/std/sync/atomic/struct.AtomicUsize.html)
     // that `usize` and `AtomicUsize` have the same representation.
     std::mem::transmute::<[usize; COUNTERS_SIZE], [AtomicUsize; COUNTERS_SIZE]>(
         [0_usize; COUNTERS_SIZE],
     )
 };
-static MEM_CNT: [AtomicUsize; COUNTERS_SIZE] = unsafe {
+pub(crate) static MEM_CNT: [AtomicUsize; COUNTERS_SIZE] = unsafe {
+    std::mem::transmute::<[usize; COUNTERS_SIZE], [AtomicUsize; COUNTERS_SIZE]>(
+        [0_usize; COUNTERS_SIZE],
+    )
+};
+/// Highest value of `MEM_SIZE` seen by each thread.
+#[cfg(feature = "metrics")]
+pub(crate) static MEM_PEAK: [AtomicUsize; COUNTERS_SIZE] = unsafe {
+    std::mem::transmute::<[usize; COUNTERS_SIZE], [AtomicUsize; COUNTERS_SIZE]>(
+        [0_usize; COUNTERS_SIZE],
+    )
+};
+/// Cumulative size and number of allocations made by each thread, never decremented.
+pub(crate) static MEM_ALLOCATED: [AtomicUsize; COUNTERS_SIZE] = unsafe {
+    std::mem::transmute::<[usize; COUNTERS_SIZE], [AtomicUsize; COUNTERS_SIZE]>(
+        [0_usize; COUNTERS_SIZE],
+    )
+};
+pub(crate) static MEM_ALLOCATED_CNT: [AtomicUsize; COUNTERS_SIZE] = unsafe {
+    std::mem::transmute::<[usize; COUNTERS_SIZE], [AtomicUsize; COUNTERS_SIZE]>(
+        [0_usize; COUNTERS_SIZE],
+    )
+};
+/// Cumulative size and number of allocations made by each thread, which were freed.
+pub(crate) static MEM_FREED: [AtomicUsize; COUNTERS_SIZE] = unsafe {
+    std::mem::transmute::<[usize; COUNTERS_SIZE], [AtomicUsize; COUNTERS_SIZE]>(
+        [0_usize; COUNTERS_SIZE],
+    )
+};
+pub(crate) static MEM_FREED_CNT: [AtomicUsize; COUNTERS_SIZE] = unsafe {
     std::mem::transmute::<[usize; COUNTERS_SIZE], [AtomicUsize; COUNTERS_SIZE]>(
         [0_usize; COUNTERS_SIZE],
     )
 };
-
-const CACHE_SIZE: usize = 1 << 20;
-static mut SKIP_CACHE: [u8; CACHE_SIZE] = [0; CACHE_SIZE];
-static mut CHECKED_CACHE: [u8; CACHE_SIZE] = [0; CACHE_SIZE];
 
 // TODO: Make stack size configurable
 const STACK_SIZE: usize = 1;
@@ -44,19 +88,20 @@ const STACK_SIZE: usize = 1;
 #[derive(Debug)]
 #[repr(C)]
 pub struct AllocHeader {
-    // TODO (magic should be split in two parts, at front and back)
+    /// Magic in the lower `MAGIC_BITS`, checksum of the other fields in the rest, see `seal`.
     magic: usize,
     size: usize,
+    /// Thread id in the lower bits, tag in the top `TAG_BITS`.
     tid: usize,
     stack: [*mut c_void; STACK_SIZE],
 }
 
 impl AllocHeader {
-    unsafe fn new(layout: Layout, tid: usize) -> Self {
+    unsafe fn new(layout: Layout, tid: usize, tag: u16, canaries: bool) -> Self {
         Self {
-            magic: MAGIC_RUST + STACK_SIZE,
+            magic: MAGIC_RUST + STACK_SIZE + if canaries { CANARY_MAGIC } else { 0 },
             size: layout.size(),
-            tid,
+            tid: (tid & TID_MASK) | (usize::from(tag) << TAG_SHIFT),
             stack: [null_mut::<c_void>(); STACK_SIZE],
         }
     }
@@ -68,7 +113,13 @@ impl AllocHeader {
 
     #[must_use]
     pub fn tid(&self) -> usize {
-        self.tid
+        self.tid & TID_MASK
+    }
+
+    /// Tag of the thread at the time of allocation, see `set_current_thread_tag`.
+    #[must_use]
+    pub fn tag(&self) -> u16 {
+        (self.tid >> TAG_SHIFT) as u16
     }
 
     #[must_use]
@@ -83,28 +134,97 @@ impl AllocHeader {
 
     #[must_use]
     pub fn is_allocated(&self) -> bool {
-        self.magic == (MAGIC_RUST + STACK_SIZE)
+        self.magic & !CANARY_MAGIC == (MAGIC_RUST + STACK_SIZE) | self.checksum()
     }
 
     #[must_use]
     pub fn is_freed(&self) -> bool {
-        self.magic == MAGIC_RUST + STACK_SIZE + FREED_MAGIC
+        self.magic & !CANARY_MAGIC == (MAGIC_RUST + STACK_SIZE + FREED_MAGIC) | self.checksum()
+    }
+
+    /// Whether the magic of an allocated or freed header is present, regardless of the checksum.
+    /// Words matching the magic are found in random data like copies of headers much more often
+    /// than ones matching the checksum as well.
+    #[must_use]
+    pub fn has_magic(&self) -> bool {
+        let magic = self.magic & MAGIC_MASK & !CANARY_MAGIC;
+        magic == MAGIC_RUST + STACK_SIZE || magic == MAGIC_RUST + STACK_SIZE + FREED_MAGIC
+    }
+
+    /// Whether the magic of a freed header is present, regardless of the checksum. Allocators may
+    /// reuse parts of freed blocks for their metadata, overwriting other fields of the header.
+    #[must_use]
+    pub fn has_freed_magic(&self) -> bool {
+        self.magic & MAGIC_MASK & !CANARY_MAGIC == MAGIC_RUST + STACK_SIZE + FREED_MAGIC
+    }
+
+    /// Checksum of `size`, `tid` and `stack` in the bits of `magic` above `MAGIC_BITS`.
+    fn checksum(&self) -> usize {
+        let h = (self.stack.iter())
+            .fold(self.size as u64 ^ (self.tid as u64).rotate_left(32), |h, ptr| {
+                h.rotate_left(21) ^ *ptr as u64
+            });
+        (murmur64(h) as usize) & !MAGIC_MASK
+    }
+
+    /// Stores the checksum of the other fields in `magic`, has to be called after they are set.
+    fn seal(&mut self) {
+        self.magic = (self.magic & MAGIC_MASK) | self.checksum();
+    }
+
+    /// Whether the block is surrounded by canaries, see `ProxyAllocator::enable_canaries`.
+    #[must_use]
+    pub(crate) fn has_canaries(&self) -> bool {
+        self.magic & CANARY_MAGIC != 0
+    }
+
+    /// Whether this is the header of a live allocation made by a version before 1.0, which had no
+    /// checksum and no canaries. The layout of headers is the same.
+    #[must_use]
+    pub fn is_legacy_allocated(&self) -> bool {
+        self.magic == LEGACY_MAGIC_RUST + STACK_SIZE
+    }
+
+    /// Same as `is_legacy_allocated` for freed allocations.
+    #[must_use]
+    pub fn is_legacy_freed(&self) -> bool {
+        self.magic == LEGACY_MAGIC_RUST + STACK_SIZE + FREED_MAGIC
+    }
+
+    /// Whether a stack trace was computed for this allocation.
+    #[must_use]
+    pub(crate) fn is_sampled(&self) -> bool {
+        !self.stack[0].is_null() && self.stack[0] != usize::MAX as *mut c_void
     }
 
     pub fn mark_as_freed(&mut self) {
-        self.magic = MAGIC_RUST + STACK_SIZE + FREED_MAGIC;
+        self.magic =
+            (self.magic & (!MAGIC_MASK | CANARY_MAGIC)) | (MAGIC_RUST + STACK_SIZE + FREED_MAGIC);
     }
 }
 
-const MAGIC_RUST: usize = 0x12_3456_7899_1100;
+const TAG_BITS: u32 = u16::BITS;
+pub(crate) const TAG_SHIFT: u32 = usize::BITS - TAG_BITS;
+pub(crate) const TID_MASK: usize = (1 << TAG_SHIFT) - 1;
+
+/// Number of lower bits of `AllocHeader::magic`, which hold the magic.
+const MAGIC_BITS: u32 = 48;
+const MAGIC_MASK: usize = (1 << MAGIC_BITS) - 1;
+const MAGIC_RUST: usize = 0x3456_7899_1100;
+/// Magic of headers written by versions before 1.0, it has the same lower `MAGIC_BITS` as
+/// `MAGIC_RUST`.
+const LEGACY_MAGIC_RUST: usize = 0x12_3456_7899_1100;
 const FREED_MAGIC: usize = 0x100;
+/// Set in the magic of blocks with canaries.
+const CANARY_MAGIC: usize = 0x400;
 
 thread_local! {
     static TID: Cell<usize> = Cell::new(0);
     static MEMORY_USAGE_MAX: Cell<usize> = Cell::new(0);
     static MEMORY_USAGE_LAST_REPORT: Cell<usize> = Cell::new(0);
     static NUM_ALLOCATIONS: Cell<usize> = Cell::new(0);
-    static IN_TRACE: Cell<usize> = Cell::new(0);
+    pub(crate) static IN_TRACE: Cell<usize> = Cell::new(0);
+    static TAG: Cell<u16> = Cell::new(0);
 }
 
 #[must_use]
@@ -128,7 +248,19 @@ pub fn get_tid() -> usize {
     })
 }
 
-fn murmur64(mut h: u64) -> u64 {
+/// Tag attached to allocations made by the current thread.
+#[must_use]
+pub fn current_thread_tag() -> u16 {
+    TAG.with(Cell::get)
+}
+
+/// Attaches `tag` to all subsequent allocations made by the current thread and returns the
+/// previous tag. Tags can be used to group allocations by component, `0` means no tag.
+pub fn set_current_thread_tag(tag: u16) -> u16 {
+    TAG.with(|t| t.replace(tag))
+}
+
+pub(crate) fn murmur64(mut h: u64) -> u64 {
     h ^= h >> 33;
     h = h.overflowing_mul(0xff51_afd7_ed55_8ccd).0;
     h ^= h >> 33;
@@ -189,11 +321,76 @@ fn skip_ptr(addr: *mut c_void) -> bool {
     found
 }
 
+/// Decides whether allocations made from `addr` should be attributed to one of its callers.
+fn should_skip(addr: *mut c_void) -> bool {
+    if addr >= SKIP_ADDR_ABOVE {
+        return true;
+    }
+    match skip_cache::get(addr) {
+        Some(skip) => skip,
+        None if !SYMBOL_RESOLUTION.load(Ordering::Relaxed) => false,
+        None if ASYNC_SYMBOL_RESOLUTION.load(Ordering::Relaxed) => {
+            // Attribute to this frame until the resolver thread tells us otherwise.
+            crate::symbol_resolver::enqueue(addr);
+            false
+        }
+        None => classify(addr),
+    }
+}
+
+/// Resolves symbols of `addr` and stores the decision in the cache.
+pub(crate) fn classify(addr: *mut c_void) -> bool {
+    let skip = skip_ptr(addr);
+    skip_cache::insert(addr, skip);
+    skip
+}
+
+/// Innermost frame of the current stack, which isn't skipped by `should_skip`.
+#[inline(always)]
+pub(crate) unsafe fn trace_site() -> *mut c_void {
+    const MISSING_TRACE: *mut c_void = 2 as *mut c_void;
+    let mut site = MISSING_TRACE;
+    let mut visit = |addr: *mut c_void| {
+        site = addr;
+        should_skip(addr)
+    };
+    if !(USE_FRAME_POINTERS.load(Ordering::Relaxed) && crate::unwind::trace(&mut visit)) {
+        // Without the global lock of `backtrace`, which is only needed on Windows. A thread in
+        // `dlopen` holds the loader lock when allocating, while a thread resolving symbols holds
+        // the lock of `backtrace` and waits for the loader lock.
+        backtrace::trace_unsynchronized(|frame| visit(frame.ip()));
+    }
+    site
+}
+
+/// Estimates the value represented by a sample of an allocation of `size` bytes.
+pub(crate) fn scale_sample(size: usize, value: usize) -> usize {
+    if size < SMALL_BLOCK_SIZE {
+        value * SMALL_BLOCK_PERIOD as usize / SMALL_BLOCK_SAMPLES as usize
+    } else {
+        value
+    }
+}
+
 #[must_use]
 pub fn total_memory_usage() -> usize {
     MEM_SIZE.iter().map(|v| v.load(Ordering::Relaxed)).sum()
 }
 
+/// Cumulative size and number of allocations made and freed since the start of the process.
+#[must_use]
+pub fn total_alloc_stats() -> AllocStats {
+    let sum = |counters: &[AtomicUsize]| -> usize {
+        counters.iter().map(|v| v.load(Ordering::Relaxed)).fold(0, usize::wrapping_add)
+    };
+    AllocStats {
+        allocated_bytes: sum(&MEM_ALLOCATED),
+        allocated_count: sum(&MEM_ALLOCATED_CNT),
+        freed_bytes: sum(&MEM_FREED),
+        freed_count: sum(&MEM_FREED_CNT),
+    }
+}
+
 pub fn current_thread_memory_usage() -> usize {
     let tid = get_tid();
 
@@ -220,11 +417,12 @@ pub fn reset_memory_usage_max() {
 
 pub struct ProxyAllocator<A> {
     inner: A,
+    pub(crate) quarantine: Mutex<Quarantine>,
 }
 
 impl<A> ProxyAllocator<A> {
     pub const fn new(inner: A) -> Self {
-        Self { inner }
+        Self { inner, quarantine: Mutex::new(Quarantine::new()) }
     }
 
     /// Enable calling `backtrace` to fill out data
@@ -233,6 +431,76 @@ impl<A> ProxyAllocator<A> {
         self
     }
 
+    /// Use frame pointers instead of `backtrace` to walk the stack.
+    ///
+    /// This is a lot faster, but requires the binary to be built with
+    /// `-C force-frame-pointers=yes`. On platforms where frame pointers can't be used we fall
+    /// back to `backtrace`.
+    pub fn enable_frame_pointers(&self, value: bool) -> &Self {
+        if value {
+            crate::unwind::load_code_ranges();
+        }
+        USE_FRAME_POINTERS.store(value, Ordering::Relaxed);
+        self
+    }
+
+    /// Resolve symbols of newly seen addresses to skip frames of the standard library and other
+    /// common crates, enabled by default. Without it allocations are attributed to the innermost
+    /// frame below the address range of shared libraries and `alloc` never resolves symbols, which
+    /// takes locks of the dynamic loader and allocates.
+    pub fn enable_symbol_resolution(&self, value: bool) -> &Self {
+        SYMBOL_RESOLUTION.store(value, Ordering::Relaxed);
+        self
+    }
+
+    /// Resolve symbols of newly seen addresses on a background thread instead of inside `alloc`.
+    ///
+    /// Until an address is resolved, allocations are attributed to it even if it belongs to a
+    /// function which would otherwise be skipped.
+    pub fn enable_async_symbol_resolution(&self, value: bool) -> &Self {
+        if value {
+            crate::symbol_resolver::start();
+        }
+        ASYNC_SYMBOL_RESOLUTION.store(value, Ordering::Relaxed);
+        self
+    }
+
+    /// Keep track of live sampled allocations, which is needed to build heap profiles.
+    pub fn enable_live_tracking(&self, value: bool) -> &Self {
+        if value {
+            live_set::USED.store(true, Ordering::Relaxed);
+        }
+        LIVE_TRACKING.store(value, Ordering::Relaxed);
+        self
+    }
+
+    /// Check headers in `dealloc` in release builds too. Double frees and frees of pointers without
+    /// a valid header are logged with a stack trace and counted in `memory_errors`, the block isn't
+    /// freed then.
+    pub fn enable_hardened_dealloc(&self, value: bool) -> &Self {
+        HARDENED.store(value, Ordering::Relaxed);
+        self
+    }
+
+    /// Surround new allocations with canaries, which are verified in `dealloc` and by
+    /// `check_canaries`. Corrupted canaries are logged with the allocation site, which is only
+    /// known if stack traces are enabled, and counted in `memory_errors`.
+    pub fn enable_canaries(&self, value: bool) -> &Self {
+        CANARIES.store(value, Ordering::Relaxed);
+        self
+    }
+
+    /// Record calls passed to `record_mmap` and `record_munmap` in a log, which
+    /// `rust-memory-analyzer` uses to attribute anonymous mappings. The site of calls is only
+    /// known if stack traces are enabled.
+    pub fn enable_mmap_log(&self, value: bool) -> &Self {
+        if value {
+            mmap_log::create();
+        }
+        MMAP_LOG.store(value, Ordering::Relaxed);
+        self
+    }
+
     pub fn set_report_usage_interval(&self, value: usize) -> &Self {
         REPORT_USAGE_INTERVAL.store(value, Ordering::Relaxed);
         self
@@ -254,13 +522,9 @@ unsafe impl<A: GlobalAlloc> GlobalAlloc for ProxyAllocator<A> {
 
         MEM_CNT[tid % COUNTERS_SIZE].fetch_add(1, Ordering::Relaxed);
 
-        MEMORY_USAGE_MAX.with(|val| {
-            if val.get() < memory_usage {
-                val.set(memory_usage);
-            }
-        });
-
-        let mut header = AllocHeader::new(layout, tid);
+        let tag = current_thread_tag();
+        let canaries = CANARIES.load(Ordering::Relaxed);
+        let mut header = AllocHeader::new(layout, tid, tag, canaries);
 
         IN_TRACE.with(|in_trace| {
             if in_trace.replace(1) != 0 {
@@ -279,31 +543,138 @@ unsafe impl<A: GlobalAlloc> GlobalAlloc for ProxyAllocator<A> {
         });
 
         let (new_layout, offset) = Layout::new::<AllocHeader>().extend(layout).unwrap();
+        let (block_layout, header_offset) =
+            if canaries { canary::block_layout(layout) } else { (new_layout, 0) };
+
+        let track = header.is_sampled() && LIVE_TRACKING.load(Ordering::Relaxed);
+        let site = header.stack[0];
+
+        header.seal();
+        let block = self.inner.alloc(block_layout);
+        if block.is_null() {
+            MEM_SIZE[tid % COUNTERS_SIZE].fetch_sub(layout.size(), Ordering::Relaxed);
+            MEM_CNT[tid % COUNTERS_SIZE].fetch_sub(1, Ordering::Relaxed);
+            return block;
+        }
+        MEMORY_USAGE_MAX.with(|val| {
+            if val.get() < memory_usage {
+                val.set(memory_usage);
+                #[cfg(feature = "metrics")]
+                MEM_PEAK[tid % COUNTERS_SIZE].fetch_max(memory_usage, Ordering::Relaxed);
+            }
+        });
+        MEM_ALLOCATED[tid % COUNTERS_SIZE].fetch_add(layout.size(), Ordering::Relaxed);
+        MEM_ALLOCATED_CNT[tid % COUNTERS_SIZE].fetch_add(1, Ordering::Relaxed);
+        histogram::record_size(tid, layout.size());
+        scope::record_alloc(layout.size(), tag);
 
-        let res = self.inner.alloc(new_layout);
+        let res = block.add(header_offset);
         *res.cast::<AllocHeader>() = header;
+        if canaries {
+            canary::arm(res, layout);
+        }
+        if track {
+            live_set::insert(res as usize, layout.size(), tid, tag, site);
+        }
 
         res.add(offset)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let (new_layout, offset) = Layout::new::<AllocHeader>().extend(layout).unwrap();
+        let (_, offset) = Layout::new::<AllocHeader>().extend(layout).unwrap();
 
         let ptr = ptr.sub(offset);
 
         let ah = &mut (*(ptr.cast::<AllocHeader>()));
+        if HARDENED.load(Ordering::Relaxed) && !ah.is_allocated() {
+            // Freeing it could corrupt the inner allocator, leaking it is safer.
+            hardened::report_invalid_free(ptr, ah);
+            return;
+        }
         debug_assert!(ah.is_allocated());
+        if ah.has_canaries() {
+            canary::disarm(ptr, layout);
+        }
+        if ah.is_sampled() && live_set::USED.load(Ordering::Relaxed) {
+            live_set::remove(ptr as usize);
+        }
         ah.mark_as_freed();
-        let header_tid = ah.tid;
+        let header_tid = ah.tid();
+        scope::record_free(layout.size(), ah.tag());
 
         MEM_SIZE[header_tid % COUNTERS_SIZE].fetch_sub(layout.size(), Ordering::Relaxed);
         MEM_CNT[header_tid % COUNTERS_SIZE].fetch_sub(1, Ordering::Relaxed);
-
-        self.inner.dealloc(ptr, new_layout);
+        MEM_FREED[header_tid % COUNTERS_SIZE].fetch_add(layout.size(), Ordering::Relaxed);
+        MEM_FREED_CNT[header_tid % COUNTERS_SIZE].fetch_add(1, Ordering::Relaxed);
+
+        if QUARANTINE.load(Ordering::Relaxed) && layout.size() <= QUARANTINE_MAX_BLOCK {
+            hardened::poison(ptr, layout);
+            let mut quarantine = self.quarantine.lock().unwrap_or_else(|err| err.into_inner());
+            // Checked again under the lock, so that no block is pushed after disabling drained
+            // the quarantine.
+            if QUARANTINE.load(Ordering::Relaxed) {
+                let evicted = quarantine.push(ptr as usize, layout);
+                drop(quarantine);
+                // Checked after unlocking, reporting allocates.
+                if let Some((header, layout)) = evicted {
+                    self.free_quarantined(header, layout);
+                }
+                return;
+            }
+        }
+        self.free_block(ptr, layout);
     }
 }
 
 impl<A: GlobalAlloc> ProxyAllocator<A> {
+    /// Keep freed blocks of up to 64 KiB filled with a poison value and reuse them only after
+    /// 1024 other blocks were freed. Writes to them are detected when they leave the quarantine
+    /// and by `check_quarantine`. Together with `enable_hardened_dealloc` this also catches double
+    /// frees of quarantined blocks in release builds. Disabling it frees all quarantined blocks.
+    pub fn enable_quarantine(&self, value: bool) -> &Self {
+        let mut quarantine = self.quarantine.lock().unwrap_or_else(|err| err.into_inner());
+        QUARANTINE.store(value, Ordering::Relaxed);
+        if !value {
+            let mut drained = std::mem::replace(&mut *quarantine, Quarantine::new());
+            // Freed after unlocking, reporting allocates.
+            drop(quarantine);
+            for (header, layout) in drained.drain() {
+                unsafe { self.free_quarantined(header, layout) };
+            }
+        }
+        self
+    }
+
+    /// Checks, whether blocks in quarantine were written to since being freed, reports them and
+    /// returns their number.
+    pub fn check_quarantine(&self) -> usize {
+        let (count, found) =
+            self.quarantine.lock().unwrap_or_else(|err| err.into_inner()).check::<16>();
+        for write in found.iter().flatten() {
+            hardened::report_write_after_free(write);
+        }
+        count
+    }
+
+    /// Verifies the poison of a block leaving the quarantine and frees it.
+    unsafe fn free_quarantined(&self, header: usize, layout: Layout) {
+        if let Some(write) = hardened::check_poison(header as *mut u8, layout) {
+            hardened::report_write_after_free(&write);
+        }
+        self.free_block(header as *mut u8, layout);
+    }
+
+    /// Frees the block with header at `header` with the layout it was allocated with.
+    unsafe fn free_block(&self, header: *mut u8, layout: Layout) {
+        if (*header.cast::<AllocHeader>()).has_canaries() {
+            let (block_layout, header_offset) = canary::block_layout(layout);
+            self.inner.dealloc(header.sub(header_offset), block_layout);
+        } else {
+            let (new_layout, _) = Layout::new::<AllocHeader>().extend(layout).unwrap();
+            self.inner.dealloc(header, new_layout);
+        }
+    }
+
     unsafe fn print_stack_trace_on_memory_spike(layout: Layout, tid: usize, memory_usage: usize) {
         MEMORY_USAGE_LAST_REPORT.with(|memory_usage_last_report| {
             if memory_usage
@@ -332,30 +703,7 @@ impl<A: GlobalAlloc> ProxyAllocator<A> {
         verbose: bool,
     ) {
         if Self::should_compute_trace(layout) {
-            const MISSING_TRACE: *mut c_void = 2 as *mut c_void;
-            stack[0] = MISSING_TRACE;
-            backtrace::trace(|frame| {
-                let addr = frame.ip();
-                stack[0] = addr.cast::<c_void>();
-                if addr >= SKIP_ADDR_ABOVE.cast::<c_void>() {
-                    true
-                } else {
-                    let hash = (murmur64(addr as u64) % (8 * CACHE_SIZE as u64)) as usize;
-                    let i = hash / 8;
-                    let cur_bit = 1 << (hash % 8);
-                    if SKIP_CACHE[i] & cur_bit != 0 {
-                        true
-                    } else if CHECKED_CACHE[i] & cur_bit != 0 {
-                        false
-                    } else if skip_ptr(addr) {
-                        SKIP_CACHE[i] |= cur_bit;
-                        true
-                    } else {
-                        CHECKED_CACHE[i] |= cur_bit;
-                        false
-                    }
-                }
-            });
+            stack[0] = trace_site();
             if verbose {
                 info!(?stack, "STARTED_TRACE");
             }
@@ -365,20 +713,19 @@ impl<A: GlobalAlloc> ProxyAllocator<A> {
     }
 
     unsafe fn should_compute_trace(layout: Layout) -> bool {
-        match layout.size() {
+        if layout.size() < SMALL_BLOCK_SIZE {
             // 1% of the time
-            0..=999 => {
-                (murmur64(NUM_ALLOCATIONS.with(|key| {
-                    // key.update() is still unstable
-                    let val = key.get();
-                    key.set(val + 1);
-                    val
-                }) as u64)
-                    % 1024)
-                    < 10
-            }
+            (murmur64(NUM_ALLOCATIONS.with(|key| {
+                // key.update() is still unstable
+                let val = key.get();
+                key.set(val + 1);
+                val
+            }) as u64)
+                % SMALL_BLOCK_PERIOD)
+                < SMALL_BLOCK_SAMPLES
+        } else {
             // 100%
-            _ => true,
+            true
         }
     }
 }
@@ -402,7 +749,10 @@ pub fn print_memory_stats() {
 
 #[cfg(test)]
 mod test {
-    use crate::allocator::{print_memory_stats, total_memory_usage, ProxyAllocator};
+    use crate::allocator::{
+        print_memory_stats, total_memory_usage, ProxyAllocator, FREED_MAGIC, LEGACY_MAGIC_RUST,
+        STACK_SIZE,
+    };
     use crate::AllocHeader;
     use std::alloc::{GlobalAlloc, Layout};
     use std::mem;
@@ -463,4 +813,125 @@ mod test {
             }
         }
     }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_frame_pointers() {
+        ALLOC.enable_stack_trace(true).enable_frame_pointers(true);
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+        let ptr = unsafe { ALLOC.alloc(layout) };
+        assert_ne!(ptr, null_mut());
+
+        let (_, offset) = Layout::new::<AllocHeader>().extend(layout).unwrap();
+        let header = unsafe { &*ptr.sub(offset).cast::<AllocHeader>() };
+        assert!(header.is_allocated());
+        assert!(!header.stack()[0].is_null());
+
+        unsafe { ALLOC.dealloc(ptr, layout) };
+        ALLOC.enable_stack_trace(false).enable_frame_pointers(false);
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_header_checksum() {
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let ptr = unsafe { ALLOC.alloc(layout) };
+        let (_, offset) = Layout::new::<AllocHeader>().extend(layout).unwrap();
+        let header = unsafe { &*ptr.sub(offset).cast::<AllocHeader>() };
+        assert!(header.is_allocated() && header.has_magic());
+
+        // A word equal to the magic followed by other data isn't a header.
+        let mut copy = unsafe { std::ptr::read(header) };
+        copy.size += 1;
+        assert!(!copy.is_allocated() && !copy.is_freed() && copy.has_magic());
+
+        let mut copy = unsafe { std::ptr::read(header) };
+        copy.mark_as_freed();
+        assert!(copy.is_freed() && !copy.is_allocated());
+
+        // Headers of old versions have the magic, but no checksum.
+        let mut copy = unsafe { std::ptr::read(header) };
+        copy.magic = LEGACY_MAGIC_RUST + STACK_SIZE;
+        assert!(copy.is_legacy_allocated() && copy.has_magic() && !copy.is_allocated());
+        copy.magic += FREED_MAGIC;
+        assert!(copy.is_legacy_freed() && !copy.is_legacy_allocated());
+        unsafe { ALLOC.dealloc(ptr, layout) };
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_async_symbol_resolution() {
+        ALLOC.enable_stack_trace(true).enable_async_symbol_resolution(true);
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+        let ptr = unsafe { ALLOC.alloc(layout) };
+        assert_ne!(ptr, null_mut());
+        unsafe { ALLOC.dealloc(ptr, layout) };
+
+        let start = std::time::Instant::now();
+        while crate::symbol_resolver::pending() != 0 {
+            assert!(start.elapsed() < std::time::Duration::from_secs(30));
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        ALLOC.enable_stack_trace(false).enable_async_symbol_resolution(false);
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_failed_alloc() {
+        struct Failing;
+        unsafe impl GlobalAlloc for Failing {
+            unsafe fn alloc(&self, _layout: Layout) -> *mut u8 {
+                null_mut()
+            }
+            unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+        }
+
+        let failing = ProxyAllocator::new(Failing);
+        let (before, usage) = (crate::total_alloc_stats(), total_memory_usage());
+        let sizes = crate::size_histogram();
+        assert_eq!(unsafe { failing.alloc(Layout::from_size_align(100, 8).unwrap()) }, null_mut());
+        let after = crate::total_alloc_stats();
+        assert_eq!(
+            (after.allocated_bytes, after.allocated_count),
+            (before.allocated_bytes, before.allocated_count)
+        );
+        assert_eq!(total_memory_usage(), usage);
+        assert_eq!(crate::size_histogram(), sizes);
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_disable_symbol_resolution() {
+        ALLOC.enable_stack_trace(true).enable_symbol_resolution(false);
+        let resolves = crate::skip_cache_stats().resolves;
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+        let ptr = unsafe { ALLOC.alloc(layout) };
+        unsafe { ALLOC.dealloc(ptr, layout) };
+        assert_eq!(crate::skip_cache_stats().resolves, resolves);
+        ALLOC.enable_stack_trace(false).enable_symbol_resolution(true);
+    }
+
+    #[test]
+    fn test_frame_pointer_trace() {
+        crate::unwind::load_code_ranges();
+        let mut frames = Vec::new();
+        let walked = unsafe {
+            crate::unwind::trace(|addr| {
+                frames.push(addr);
+                true
+            })
+        };
+        let mut ips = Vec::new();
+        backtrace::trace(|frame| {
+            ips.push(frame.ip());
+            true
+        });
+        assert_eq!(walked, !frames.is_empty());
+        assert!(frames.len() <= 128);
+        // The caller of this test is found by both unwinders, later frames may lack frame
+        // pointers.
+        if let Some(first) = frames.first() {
+            assert!(ips.contains(first), "{:?} not in {:?}", first, ips);
+        }
+    }
 }
@@ -0,0 +1,212 @@
+//! Canaries detecting heap buffer overflows.
+//!
+//! With canaries enabled, blocks get a word right before the header and one right after the user
+//! region, both derived from their address, so that copies of canaries don't match. Overflows of
+//! the preceding block hit the first one before the header, overflows of the block itself the
+//! second one. Blocks with canaries are flagged in the magic of their header, so that they can be
+//! freed after canaries were disabled.
+//!
+//! Canaries are verified in `dealloc`. All blocks with canaries are also recorded in a table keyed
+//! by the address of their header, which `check_canaries` walks.
+use crate::allocator::{murmur64, AllocHeader};
+use crate::hardened;
+use std::alloc::Layout;
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+/// Add canaries to new allocations.
+pub(crate) static CANARIES: AtomicBool = AtomicBool::new(false);
+pub(crate) static CORRUPTED_CANARIES: AtomicUsize = AtomicUsize::new(0);
+
+const CANARY_SEED: u64 = 0x5bd1_e995_c0ff_ee00;
+const CANARY_SIZE: usize = std::mem::size_of::<u64>();
+
+const BLOCKS_SIZE: usize = 1 << 20;
+/// Number of consecutive slots a block can occupy.
+const MAX_PROBE: usize = 16;
+/// Key of a slot, which is being filled in.
+const RESERVED: usize = 1;
+
+// SAFETY (for all transmutes below): `usize` and `AtomicUsize` have the same representation.
+static KEYS: [AtomicUsize; BLOCKS_SIZE] = unsafe {
+    std::mem::transmute::<[usize; BLOCKS_SIZE], [AtomicUsize; BLOCKS_SIZE]>([0_usize; BLOCKS_SIZE])
+};
+/// Offset of the user region from the header.
+static OFFSETS: [AtomicUsize; BLOCKS_SIZE] = unsafe {
+    std::mem::transmute::<[usize; BLOCKS_SIZE], [AtomicUsize; BLOCKS_SIZE]>([0_usize; BLOCKS_SIZE])
+};
+/// Size of the user region, kept here as the size in the header may be corrupted.
+static SIZES: [AtomicUsize; BLOCKS_SIZE] = unsafe {
+    std::mem::transmute::<[usize; BLOCKS_SIZE], [AtomicUsize; BLOCKS_SIZE]>([0_usize; BLOCKS_SIZE])
+};
+/// Blocks, which didn't fit into the table and are only checked in `dealloc`.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+/// Taken for writing while walking the table, `dealloc` takes it for reading to remove a block,
+/// so that no block is freed while being checked.
+static WALK: RwLock<()> = RwLock::new(());
+
+/// Layout of a block with canaries and the offset of its header.
+///
+/// The header is preceded by as many bytes as the alignment requires to keep the user region
+/// aligned, but at least by the header canary.
+pub(crate) fn block_layout(layout: Layout) -> (Layout, usize) {
+    let (with_header, offset) = Layout::new::<AllocHeader>().extend(layout).unwrap();
+    let prefix = with_header.align().max(CANARY_SIZE);
+    let size = prefix + offset + layout.size() + CANARY_SIZE;
+    (Layout::from_size_align(size, with_header.align()).unwrap(), prefix)
+}
+
+fn canary(address: usize) -> u64 {
+    murmur64(address as u64 ^ CANARY_SEED)
+}
+
+fn canary_addresses(header: usize, offset: usize, size: usize) -> [usize; 2] {
+    [header - CANARY_SIZE, header + offset + size]
+}
+
+/// Writes canaries of the block with header at `header` and records it in the table.
+pub(crate) unsafe fn arm(header: *mut u8, layout: Layout) {
+    let (_, offset) = Layout::new::<AllocHeader>().extend(layout).unwrap();
+    for address in canary_addresses(header as usize, offset, layout.size()) {
+        (address as *mut u64).write_unaligned(canary(address));
+    }
+
+    let home = murmur64(header as u64) as usize % BLOCKS_SIZE;
+    for i in 0..MAX_PROBE {
+        let idx = (home + i) % BLOCKS_SIZE;
+        if KEYS[idx].compare_exchange(0, RESERVED, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            OFFSETS[idx].store(offset, Ordering::Relaxed);
+            SIZES[idx].store(layout.size(), Ordering::Relaxed);
+            KEYS[idx].store(header as usize, Ordering::Release);
+            return;
+        }
+    }
+    DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Removes the block with header at `header` from the table and reports corrupted canaries. Has to
+/// be called before the block is freed.
+pub(crate) unsafe fn disarm(header: *mut u8, layout: Layout) {
+    {
+        let _walk = WALK.read().unwrap_or_else(|err| err.into_inner());
+        let home = murmur64(header as u64) as usize % BLOCKS_SIZE;
+        for i in 0..MAX_PROBE {
+            let idx = (home + i) % BLOCKS_SIZE;
+            if KEYS[idx]
+                .compare_exchange(header as usize, 0, Ordering::Release, Ordering::Relaxed)
+                .is_ok()
+            {
+                break;
+            }
+        }
+    }
+    let (_, offset) = Layout::new::<AllocHeader>().extend(layout).unwrap();
+    if let Some(corruption) = check(header as usize, offset, layout.size()) {
+        report(&corruption);
+    }
+}
+
+/// Block with a corrupted canary or header.
+#[derive(Debug, Clone, Copy)]
+struct Corruption {
+    ptr: *mut u8,
+    size: usize,
+    site: *mut c_void,
+    corrupted: &'static str,
+}
+
+unsafe fn check(header: usize, offset: usize, size: usize) -> Option<Corruption> {
+    let ah = &*(header as *const AllocHeader);
+    let [before, after] = canary_addresses(header, offset, size);
+    let corrupted = if (before as *const u64).read() != canary(before) {
+        "header canary"
+    } else if !ah.is_allocated() {
+        "header"
+    } else if (after as *const u64).read_unaligned() != canary(after) {
+        "trailing canary"
+    } else {
+        return None;
+    };
+    Some(Corruption { ptr: (header + offset) as *mut u8, size, site: ah.stack()[0], corrupted })
+}
+
+fn report(corruption: &Corruption) {
+    CORRUPTED_CANARIES.fetch_add(1, Ordering::Relaxed);
+    let Corruption { ptr, size, site, corrupted } = *corruption;
+    hardened::report(|bt| {
+        tracing::error!(?ptr, size, ?site, corrupted, ?bt, "heap buffer overflow");
+    });
+}
+
+/// Checks canaries of all live blocks allocated with canaries enabled, reports corrupted ones
+/// with their allocation site and returns their number.
+///
+/// Blocks can't be freed while this runs, blocks allocated concurrently may or may not be checked.
+pub fn check_canaries() -> usize {
+    const REPORTED: usize = 16;
+    let mut found = [None; REPORTED];
+    let mut count = 0;
+    {
+        // Reporting allocates and frees, which can't happen while the table is locked.
+        let _walk = WALK.write().unwrap_or_else(|err| err.into_inner());
+        for idx in 0..BLOCKS_SIZE {
+            let header = KEYS[idx].load(Ordering::Acquire);
+            if header <= RESERVED {
+                continue;
+            }
+            let offset = OFFSETS[idx].load(Ordering::Relaxed);
+            let size = SIZES[idx].load(Ordering::Relaxed);
+            if let Some(corruption) = unsafe { check(header, offset, size) } {
+                if count < REPORTED {
+                    found[count] = Some(corruption);
+                }
+                count += 1;
+            }
+        }
+    }
+    for corruption in found.iter().flatten() {
+        report(corruption);
+    }
+    CORRUPTED_CANARIES.fetch_add(count.saturating_sub(REPORTED), Ordering::Relaxed);
+    let dropped = DROPPED.load(Ordering::Relaxed);
+    if dropped != 0 {
+        tracing::warn!(dropped, "blocks with canaries are only checked when freed");
+    }
+    count
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{check_canaries, memory_errors, ProxyAllocator};
+    use std::alloc::{GlobalAlloc, Layout};
+
+    static ALLOC: ProxyAllocator<tikv_jemallocator::Jemalloc> =
+        ProxyAllocator::new(tikv_jemallocator::Jemalloc);
+
+    #[test]
+    #[serial_test::serial]
+    fn test_canaries() {
+        ALLOC.enable_canaries(true);
+        let before = memory_errors().corrupted_canaries;
+        for alignment in (0..13).map(|i| 1 << i) {
+            let layout = Layout::from_size_align(33, alignment).unwrap();
+            let ptr = unsafe { ALLOC.alloc(layout) };
+            assert_eq!(ptr as usize % alignment, 0);
+            unsafe { ptr.write_bytes(1, layout.size()) };
+            assert_eq!(check_canaries(), 0);
+            unsafe { ALLOC.dealloc(ptr, layout) };
+        }
+        assert_eq!(memory_errors().corrupted_canaries, before);
+
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let ptr = unsafe { ALLOC.alloc(layout) };
+        unsafe { ptr.add(layout.size()).write(0) };
+        assert_eq!(check_canaries(), 1);
+        // Blocks with canaries can be freed after disabling them, they are checked once more.
+        ALLOC.enable_canaries(false);
+        unsafe { ALLOC.dealloc(ptr, layout) };
+        assert_eq!(check_canaries(), 0);
+        assert_eq!(memory_errors().corrupted_canaries, before + 2);
+    }
+}
@@ -0,0 +1,219 @@
+//! Hardened deallocation: detection of double frees, frees of pointers not allocated by the
+//! proxy and writes to freed memory.
+use crate::allocator::{AllocHeader, IN_TRACE};
+use crate::canary::CORRUPTED_CANARIES;
+use backtrace::Backtrace;
+use std::alloc::Layout;
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Check headers in `dealloc` in release builds too.
+pub(crate) static HARDENED: AtomicBool = AtomicBool::new(false);
+/// Delay reuse of freed blocks and poison them.
+pub(crate) static QUARANTINE: AtomicBool = AtomicBool::new(false);
+
+static DOUBLE_FREES: AtomicUsize = AtomicUsize::new(0);
+static INVALID_FREES: AtomicUsize = AtomicUsize::new(0);
+static WRITES_AFTER_FREE: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of blocks kept in quarantine.
+const QUARANTINE_SIZE: usize = 1024;
+/// Larger blocks are freed right away, so that the quarantine holds at most 64 MiB.
+pub(crate) const QUARANTINE_MAX_BLOCK: usize = 64 << 10;
+/// Value freed blocks are filled with while in quarantine.
+const POISON: u8 = 0xdb;
+
+/// Memory errors detected since the start of the process.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryErrors {
+    /// Blocks freed again, detected by their freed header.
+    pub double_frees: usize,
+    /// Pointers freed without a valid header, e.g. allocated by another allocator, or blocks with a
+    /// corrupted header.
+    pub invalid_frees: usize,
+    /// Quarantined blocks, which were written to after being freed.
+    pub writes_after_free: usize,
+    /// Blocks with a corrupted canary or header, found in `dealloc` or by `check_canaries`.
+    pub corrupted_canaries: usize,
+}
+
+/// Memory errors detected with `enable_hardened_dealloc`, `enable_quarantine` and
+/// `enable_canaries`.
+#[must_use]
+pub fn memory_errors() -> MemoryErrors {
+    MemoryErrors {
+        double_frees: DOUBLE_FREES.load(Ordering::Relaxed),
+        invalid_frees: INVALID_FREES.load(Ordering::Relaxed),
+        writes_after_free: WRITES_AFTER_FREE.load(Ordering::Relaxed),
+        corrupted_canaries: CORRUPTED_CANARIES.load(Ordering::Relaxed),
+    }
+}
+
+/// Freed blocks, whose reuse is delayed. Stored inline, because the quarantine can't allocate.
+#[derive(Debug)]
+pub(crate) struct Quarantine {
+    /// Header address and layout requested by the user of each block.
+    blocks: [Option<(usize, Layout)>; QUARANTINE_SIZE],
+    next: usize,
+}
+
+impl Quarantine {
+    pub(crate) const fn new() -> Self {
+        Self { blocks: [None; QUARANTINE_SIZE], next: 0 }
+    }
+
+    /// Adds a poisoned block and returns the oldest one, if the quarantine is full.
+    pub(crate) fn push(&mut self, header: usize, layout: Layout) -> Option<(usize, Layout)> {
+        let evicted = self.blocks[self.next].replace((header, layout));
+        self.next = (self.next + 1) % QUARANTINE_SIZE;
+        evicted
+    }
+
+    /// Removes all blocks.
+    pub(crate) fn drain(&mut self) -> impl Iterator<Item = (usize, Layout)> + '_ {
+        self.blocks.iter_mut().filter_map(Option::take)
+    }
+
+    /// Checks poison of all blocks and poisons them again. Returns the number of blocks written
+    /// to and details of up to `N` of them, which can be reported once the quarantine is unlocked.
+    pub(crate) fn check<const N: usize>(&mut self) -> (usize, [Option<WriteAfterFree>; N]) {
+        let mut found = [None; N];
+        let mut count = 0;
+        for &(header, layout) in self.blocks.iter().flatten() {
+            if let Some(write) = unsafe { check_poison(header as *mut u8, layout) } {
+                if count < N {
+                    found[count] = Some(write);
+                }
+                count += 1;
+                unsafe { poison(header as *mut u8, layout) };
+            }
+        }
+        (count, found)
+    }
+}
+
+/// Block found to be written to after being freed.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct WriteAfterFree {
+    ptr: *mut u8,
+    size: usize,
+    /// Offset of the first modified byte.
+    offset: usize,
+    site: *mut c_void,
+}
+
+/// Fills the user part of the block with header at `header` with `POISON`.
+pub(crate) unsafe fn poison(header: *mut u8, layout: Layout) {
+    let (_, offset) = Layout::new::<AllocHeader>().extend(layout).unwrap();
+    header.add(offset).write_bytes(POISON, layout.size());
+}
+
+/// Returns the first write to the block with header at `header` since it was poisoned.
+pub(crate) unsafe fn check_poison(header: *mut u8, layout: Layout) -> Option<WriteAfterFree> {
+    let (_, offset) = Layout::new::<AllocHeader>().extend(layout).unwrap();
+    let ptr = header.add(offset);
+    let data = std::slice::from_raw_parts(ptr, layout.size());
+    let modified = data.iter().position(|&b| b != POISON)?;
+    let site = (*header.cast::<AllocHeader>()).stack()[0];
+    Some(WriteAfterFree { ptr, size: layout.size(), offset: modified, site })
+}
+
+/// Logs an error with the current stack trace, without tracing allocations made for it.
+pub(crate) fn report(log: impl FnOnce(&Backtrace)) {
+    IN_TRACE.with(|in_trace| {
+        let prev = in_trace.replace(1);
+        log(&Backtrace::new());
+        in_trace.set(prev);
+    });
+}
+
+/// Reports freeing `ptr`, whose header `ah` isn't the one of an allocated block.
+pub(crate) fn report_invalid_free(ptr: *mut u8, ah: &AllocHeader) {
+    if ah.is_freed() {
+        DOUBLE_FREES.fetch_add(1, Ordering::Relaxed);
+        report(|bt| {
+            tracing::error!(?ptr, size = ah.size(), site = ?ah.stack()[0], ?bt, "double free");
+        });
+    } else {
+        INVALID_FREES.fetch_add(1, Ordering::Relaxed);
+        report(|bt| tracing::error!(?ptr, ?bt, "free of a pointer without a valid header"));
+    }
+}
+
+pub(crate) fn report_write_after_free(write: &WriteAfterFree) {
+    WRITES_AFTER_FREE.fetch_add(1, Ordering::Relaxed);
+    let WriteAfterFree { ptr, size, offset, site } = *write;
+    report(|bt| {
+        tracing::error!(?ptr, size, offset, ?site, ?bt, "write after free");
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{memory_errors, ProxyAllocator};
+    use std::alloc::{GlobalAlloc, Layout};
+
+    static ALLOC: ProxyAllocator<tikv_jemallocator::Jemalloc> =
+        ProxyAllocator::new(tikv_jemallocator::Jemalloc);
+
+    #[test]
+    #[serial_test::serial]
+    fn test_double_free() {
+        ALLOC.enable_hardened_dealloc(true).enable_quarantine(true);
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let before = memory_errors();
+        unsafe {
+            let ptr = ALLOC.alloc(layout);
+            ALLOC.dealloc(ptr, layout);
+            ALLOC.dealloc(ptr, layout);
+        }
+        assert_eq!(memory_errors().double_frees, before.double_frees + 1);
+
+        let mut not_allocated = [0u64; 16];
+        unsafe { ALLOC.dealloc(not_allocated.as_mut_ptr().add(8).cast(), layout) };
+        assert_eq!(memory_errors().invalid_frees, before.invalid_frees + 1);
+        ALLOC.enable_hardened_dealloc(false).enable_quarantine(false);
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_write_after_free() {
+        ALLOC.enable_quarantine(true);
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let before = memory_errors();
+        unsafe {
+            let ptr = ALLOC.alloc(layout);
+            ALLOC.dealloc(ptr, layout);
+            assert_eq!(ALLOC.check_quarantine(), 0);
+            ptr.add(10).write(1);
+        }
+        assert_eq!(ALLOC.check_quarantine(), 1);
+        // The block is poisoned again after being reported.
+        assert_eq!(ALLOC.check_quarantine(), 0);
+        assert_eq!(memory_errors().writes_after_free, before.writes_after_free + 1);
+        ALLOC.enable_quarantine(false);
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_disable_quarantine_while_freeing() {
+        ALLOC.enable_quarantine(true);
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        unsafe { ALLOC.dealloc(ALLOC.alloc(layout), layout) };
+                    }
+                })
+            })
+            .collect();
+        ALLOC.enable_quarantine(false);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // No block was pushed after the quarantine was drained.
+        let quarantine = ALLOC.quarantine.lock().unwrap();
+        assert!(quarantine.blocks.iter().all(Option::is_none));
+    }
+}
@@ -0,0 +1,117 @@
+//! Power-of-two histograms of allocation sizes and lifetimes.
+//!
+//! Sizes of all allocations are counted in shards selected by thread id, so threads mostly
+//! increment their own cache lines. Lifetimes are only known for allocations in the live
+//! allocation registry, so they are counted when such an allocation is freed.
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of buckets, bucket `i` counts values in `(2^(i-1), 2^i]`, bucket `0` values up to `1`
+/// and the last bucket everything above `2^62`.
+pub const HISTOGRAM_BUCKETS: usize = 64;
+const SHARDS: usize = 16;
+
+// SAFETY (for all transmutes below): `usize` and `AtomicUsize` have the same representation.
+static SIZES: [AtomicUsize; SHARDS * HISTOGRAM_BUCKETS] = unsafe {
+    std::mem::transmute::<
+        [usize; SHARDS * HISTOGRAM_BUCKETS],
+        [AtomicUsize; SHARDS * HISTOGRAM_BUCKETS],
+    >([0_usize; SHARDS * HISTOGRAM_BUCKETS])
+};
+/// Lifetimes in nanoseconds.
+static LIFETIMES: [AtomicUsize; HISTOGRAM_BUCKETS] = unsafe {
+    std::mem::transmute::<[usize; HISTOGRAM_BUCKETS], [AtomicUsize; HISTOGRAM_BUCKETS]>(
+        [0_usize; HISTOGRAM_BUCKETS],
+    )
+};
+static LIFETIMES_SUM: AtomicUsize = AtomicUsize::new(0);
+
+/// Snapshot of a histogram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Histogram {
+    pub buckets: [usize; HISTOGRAM_BUCKETS],
+    /// Sum of all counted values.
+    pub sum: usize,
+}
+
+impl Histogram {
+    /// Inclusive upper bound of values counted in bucket `idx`.
+    #[must_use]
+    pub fn upper_bound(idx: usize) -> usize {
+        if idx + 1 >= HISTOGRAM_BUCKETS {
+            usize::MAX
+        } else {
+            1 << idx
+        }
+    }
+
+    /// Number of counted values.
+    #[must_use]
+    pub fn count(&self) -> usize {
+        self.buckets.iter().sum()
+    }
+}
+
+fn bucket(value: usize) -> usize {
+    ((usize::BITS - value.saturating_sub(1).leading_zeros()) as usize).min(HISTOGRAM_BUCKETS - 1)
+}
+
+pub(crate) fn record_size(tid: usize, size: usize) {
+    SIZES[tid % SHARDS * HISTOGRAM_BUCKETS + bucket(size)].fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn record_lifetime(nanos: usize) {
+    LIFETIMES[bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+    LIFETIMES_SUM.fetch_add(nanos, Ordering::Relaxed);
+}
+
+/// Nanoseconds since an arbitrary point in the past, used to measure lifetimes.
+pub(crate) fn now_nanos() -> usize {
+    use nix::libc;
+
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: `ts` is a valid `timespec` to write to.
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    (ts.tv_sec as usize).wrapping_mul(1_000_000_000).wrapping_add(ts.tv_nsec as usize)
+}
+
+/// Histogram of sizes of all allocations made since the start of the process.
+#[must_use]
+pub fn size_histogram() -> Histogram {
+    let mut buckets = [0; HISTOGRAM_BUCKETS];
+    for (idx, count) in SIZES.iter().enumerate() {
+        buckets[idx % HISTOGRAM_BUCKETS] += count.load(Ordering::Relaxed);
+    }
+    Histogram { buckets, sum: crate::total_alloc_stats().allocated_bytes }
+}
+
+/// Histogram of lifetimes in nanoseconds of freed sampled allocations.
+///
+/// Only allocations recorded in the live allocation registry are counted, which requires
+/// `ProxyAllocator::enable_live_tracking`.
+#[must_use]
+pub fn lifetime_histogram() -> Histogram {
+    Histogram {
+        buckets: std::array::from_fn(|idx| LIFETIMES[idx].load(Ordering::Relaxed)),
+        sum: LIFETIMES_SUM.load(Ordering::Relaxed),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::histogram::{bucket, Histogram, HISTOGRAM_BUCKETS};
+
+    #[test]
+    fn test_buckets() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(1), 0);
+        assert_eq!(bucket(2), 1);
+        assert_eq!(bucket(1024), 10);
+        assert_eq!(bucket(1025), 11);
+        assert_eq!(bucket(usize::MAX), HISTOGRAM_BUCKETS - 1);
+        for value in [1, 2, 3, 1000, 1 << 40] {
+            let idx = bucket(value);
+            assert!(value <= Histogram::upper_bound(idx));
+            assert!(idx == 0 || value > Histogram::upper_bound(idx - 1));
+        }
+    }
+}
@@ -1,7 +1,34 @@
 mod allocator;
+mod canary;
+mod hardened;
+mod histogram;
+mod live_set;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+mod mmap_log;
+#[cfg(feature = "pprof")]
+pub mod pprof;
+mod scope;
+mod skip_cache;
+mod symbol_resolver;
+mod unwind;
 
 pub use allocator::{
-    current_thread_memory_usage, current_thread_peak_memory_usage, get_tid, print_memory_stats,
-    reset_memory_usage_max, thread_memory_count, thread_memory_usage, total_memory_usage,
-    AllocHeader, ProxyAllocator,
+    current_thread_memory_usage, current_thread_peak_memory_usage, current_thread_tag, get_tid,
+    print_memory_stats, reset_memory_usage_max, set_current_thread_tag, thread_memory_count,
+    thread_memory_usage, total_alloc_stats, total_memory_usage, AllocHeader, ProxyAllocator,
 };
+pub use canary::check_canaries;
+pub use hardened::{memory_errors, MemoryErrors};
+pub use histogram::{lifetime_histogram, size_histogram, Histogram, HISTOGRAM_BUCKETS};
+pub use live_set::{
+    for_each_live_allocation, live_allocations, live_allocations_by, live_allocations_dropped,
+    GroupBy, LiveAllocation, LiveTotals,
+};
+pub use mmap_log::{
+    mmap_log_size, parse_live_mappings, parse_mmap_log, record_mmap, record_munmap, LiveMapping,
+    MmapEvent, MmapKind, MMAP_LOG_CAPACITY, MMAP_LOG_HEADER_SIZE, MMAP_LOG_MAGIC,
+    MMAP_TABLE_CAPACITY,
+};
+pub use scope::{AllocScope, AllocStats};
+pub use skip_cache::{skip_cache_stats, SkipCacheStats};
@@ -0,0 +1,260 @@
+//! Registry of live sampled allocations.
+//!
+//! Allocations, for which a stack trace was computed, are recorded in an open-addressed table of
+//! atomics keyed by the address of their header. Together with the per-site totals of all sampled
+//! allocations this is enough to build a heap profile from inside the process.
+//!
+//! Records are copies of the header fields, so reading the registry never touches memory, which
+//! may have been freed in the meantime.
+use crate::allocator::{murmur64, scale_sample, TAG_SHIFT, TID_MASK};
+use crate::histogram;
+use std::collections::HashMap;
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+const LIVE_SET_SIZE: usize = 1 << 20;
+/// Number of consecutive slots an allocation can occupy.
+const MAX_PROBE: usize = 16;
+/// Key of a slot, which is being filled in.
+const RESERVED: usize = 1;
+
+/// Set once live tracking was enabled, after that `dealloc` has to keep the registry up to date.
+pub(crate) static USED: AtomicBool = AtomicBool::new(false);
+
+// SAFETY (for all transmutes below): `usize` and `AtomicUsize` have the same representation.
+static KEYS: [AtomicUsize; LIVE_SET_SIZE] = unsafe {
+    std::mem::transmute::<[usize; LIVE_SET_SIZE], [AtomicUsize; LIVE_SET_SIZE]>(
+        [0_usize; LIVE_SET_SIZE],
+    )
+};
+static SIZES: [AtomicUsize; LIVE_SET_SIZE] = unsafe {
+    std::mem::transmute::<[usize; LIVE_SET_SIZE], [AtomicUsize; LIVE_SET_SIZE]>(
+        [0_usize; LIVE_SET_SIZE],
+    )
+};
+static SITES: [AtomicUsize; LIVE_SET_SIZE] = unsafe {
+    std::mem::transmute::<[usize; LIVE_SET_SIZE], [AtomicUsize; LIVE_SET_SIZE]>(
+        [0_usize; LIVE_SET_SIZE],
+    )
+};
+/// Thread id and tag, packed the same way as in `AllocHeader`.
+static TIDS: [AtomicUsize; LIVE_SET_SIZE] = unsafe {
+    std::mem::transmute::<[usize; LIVE_SET_SIZE], [AtomicUsize; LIVE_SET_SIZE]>(
+        [0_usize; LIVE_SET_SIZE],
+    )
+};
+/// Time of allocation, see `histogram::now_nanos`.
+static TIMES: [AtomicUsize; LIVE_SET_SIZE] = unsafe {
+    std::mem::transmute::<[usize; LIVE_SET_SIZE], [AtomicUsize; LIVE_SET_SIZE]>(
+        [0_usize; LIVE_SET_SIZE],
+    )
+};
+/// Allocations, which didn't fit into the registry.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+const SITES_SIZE: usize = 1 << 14;
+/// Cumulative number and size of all sampled allocations made from a given site, scaled to
+/// account for sampling.
+static SITE_ADDRS: [AtomicUsize; SITES_SIZE] = unsafe {
+    std::mem::transmute::<[usize; SITES_SIZE], [AtomicUsize; SITES_SIZE]>([0_usize; SITES_SIZE])
+};
+static SITE_COUNTS: [AtomicUsize; SITES_SIZE] = unsafe {
+    std::mem::transmute::<[usize; SITES_SIZE], [AtomicUsize; SITES_SIZE]>([0_usize; SITES_SIZE])
+};
+static SITE_SIZES: [AtomicUsize; SITES_SIZE] = unsafe {
+    std::mem::transmute::<[usize; SITES_SIZE], [AtomicUsize; SITES_SIZE]>([0_usize; SITES_SIZE])
+};
+
+/// Copy of the header of a live sampled allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LiveAllocation {
+    /// Address of the allocation header.
+    pub header: usize,
+    pub size: usize,
+    pub tid: usize,
+    pub tag: u16,
+    /// Frame the allocation is attributed to.
+    pub site: *mut c_void,
+}
+
+/// Key used to aggregate live allocations with `live_allocations_by`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupBy {
+    /// Address of the frame the allocation is attributed to.
+    Stack,
+    Tag,
+    Thread,
+}
+
+/// Number and total size of live sampled allocations in a group.
+///
+/// These are raw numbers of sampled allocations, small allocations are only sampled
+/// occasionally, so only allocations of at least 1000 bytes are counted exactly.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LiveTotals {
+    pub count: usize,
+    pub size: usize,
+}
+
+/// Records a new sampled allocation with its header at `header`.
+pub(crate) fn insert(header: usize, size: usize, tid: usize, tag: u16, site: *mut c_void) {
+    add_to_site(site as usize, size);
+
+    let home = murmur64(header as u64) as usize % LIVE_SET_SIZE;
+    for i in 0..MAX_PROBE {
+        let idx = (home + i) % LIVE_SET_SIZE;
+        if KEYS[idx].compare_exchange(0, RESERVED, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            SIZES[idx].store(size, Ordering::Relaxed);
+            SITES[idx].store(site as usize, Ordering::Relaxed);
+            TIMES[idx].store(histogram::now_nanos(), Ordering::Relaxed);
+            TIDS[idx].store((tid & TID_MASK) | (usize::from(tag) << TAG_SHIFT), Ordering::Relaxed);
+            KEYS[idx].store(header, Ordering::Release);
+            return;
+        }
+    }
+    DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Removes the allocation with its header at `header`, if it's in the registry, and records its
+/// lifetime.
+pub(crate) fn remove(header: usize) {
+    let home = murmur64(header as u64) as usize % LIVE_SET_SIZE;
+    for i in 0..MAX_PROBE {
+        let idx = (home + i) % LIVE_SET_SIZE;
+        if KEYS[idx].load(Ordering::Acquire) != header {
+            continue;
+        }
+        // Nobody else can reuse the slot until we release it.
+        let allocated_at = TIMES[idx].load(Ordering::Relaxed);
+        if KEYS[idx].compare_exchange(header, 0, Ordering::Release, Ordering::Relaxed).is_ok() {
+            histogram::record_lifetime(histogram::now_nanos().saturating_sub(allocated_at));
+            return;
+        }
+    }
+}
+
+/// Calls `f` with each live sampled allocation.
+///
+/// Requires live tracking to be enabled with `ProxyAllocator::enable_live_tracking`. Allocations
+/// made or freed concurrently may or may not be reported.
+pub fn for_each_live_allocation(mut f: impl FnMut(LiveAllocation)) {
+    for idx in 0..LIVE_SET_SIZE {
+        let header = KEYS[idx].load(Ordering::Acquire);
+        if header <= RESERVED {
+            continue;
+        }
+        let tid = TIDS[idx].load(Ordering::Relaxed);
+        let record = LiveAllocation {
+            header,
+            size: SIZES[idx].load(Ordering::Relaxed),
+            tid: tid & TID_MASK,
+            tag: (tid >> TAG_SHIFT) as u16,
+            site: SITES[idx].load(Ordering::Relaxed) as *mut c_void,
+        };
+        // Skip the record if the slot was reused while we were reading it.
+        if KEYS[idx].load(Ordering::Acquire) == header {
+            f(record);
+        }
+    }
+}
+
+/// Returns all live sampled allocations.
+#[must_use]
+pub fn live_allocations() -> Vec<LiveAllocation> {
+    let mut result = Vec::new();
+    for_each_live_allocation(|record| result.push(record));
+    result
+}
+
+/// Aggregates live sampled allocations by stack, tag or thread.
+///
+/// Keys are frame addresses for `GroupBy::Stack`, tags for `GroupBy::Tag` and thread ids for
+/// `GroupBy::Thread`.
+#[must_use]
+pub fn live_allocations_by(group_by: GroupBy) -> HashMap<usize, LiveTotals> {
+    let mut result: HashMap<usize, LiveTotals> = HashMap::new();
+    for_each_live_allocation(|record| {
+        let key = match group_by {
+            GroupBy::Stack => record.site as usize,
+            GroupBy::Tag => usize::from(record.tag),
+            GroupBy::Thread => record.tid,
+        };
+        let totals = result.entry(key).or_default();
+        totals.count += 1;
+        totals.size += record.size;
+    });
+    result
+}
+
+/// Number of sampled allocations, which were not recorded because the registry was full.
+///
+/// If it's not zero, results of `live_allocations` are incomplete.
+#[must_use]
+pub fn live_allocations_dropped() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+fn add_to_site(site: usize, size: usize) {
+    let home = murmur64(site as u64) as usize % SITES_SIZE;
+    for i in 0..MAX_PROBE {
+        let idx = (home + i) % SITES_SIZE;
+        match SITE_ADDRS[idx].compare_exchange(0, site, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => {}
+            Err(current) if current == site => {}
+            Err(_) => continue,
+        }
+        SITE_COUNTS[idx].fetch_add(scale_sample(size, 1), Ordering::Relaxed);
+        SITE_SIZES[idx].fetch_add(scale_sample(size, size), Ordering::Relaxed);
+        return;
+    }
+}
+
+/// Calls `f` with the site, estimated count and total size of all allocations made from each
+/// sampled site.
+#[cfg_attr(not(feature = "pprof"), allow(dead_code))]
+pub(crate) fn for_each_site(mut f: impl FnMut(*mut c_void, usize, usize)) {
+    for idx in 0..SITES_SIZE {
+        let site = SITE_ADDRS[idx].load(Ordering::Relaxed);
+        if site != 0 {
+            f(
+                site as *mut c_void,
+                SITE_COUNTS[idx].load(Ordering::Relaxed),
+                SITE_SIZES[idx].load(Ordering::Relaxed),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        lifetime_histogram, live_allocations_by, set_current_thread_tag, GroupBy, LiveTotals,
+        ProxyAllocator,
+    };
+    use std::alloc::{GlobalAlloc, Layout};
+
+    static ALLOC: ProxyAllocator<tikv_jemallocator::Jemalloc> =
+        ProxyAllocator::new(tikv_jemallocator::Jemalloc);
+
+    #[test]
+    #[serial_test::serial]
+    fn test_live_allocations_by_tag() {
+        const TAG: u16 = 0x1234;
+        ALLOC.enable_stack_trace(true).enable_live_tracking(true);
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+        let previous = set_current_thread_tag(TAG);
+        let ptrs: Vec<_> = (0..3).map(|_| unsafe { ALLOC.alloc(layout) }).collect();
+        set_current_thread_tag(previous);
+
+        let by_tag = live_allocations_by(GroupBy::Tag);
+        assert_eq!(by_tag.get(&usize::from(TAG)), Some(&LiveTotals { count: 3, size: 3 * 4096 }));
+
+        let lifetimes = lifetime_histogram().count();
+        for ptr in ptrs {
+            unsafe { ALLOC.dealloc(ptr, layout) };
+        }
+        assert!(lifetime_histogram().count() >= lifetimes + 3);
+        assert_eq!(live_allocations_by(GroupBy::Tag).get(&usize::from(TAG)), None);
+        ALLOC.enable_stack_trace(false).enable_live_tracking(false);
+    }
+}
@@ -0,0 +1,284 @@
+//! Exporting proxy counters in the OpenMetrics text format understood by Prometheus.
+//!
+//! Rendering only reads atomic counters, so it can be done concurrently with allocations and with
+//! other scrapes. Per-thread counters are grouped by thread name, which is read from `/proc`, and
+//! counters of threads, which have exited, are reported under the `exited` name.
+use crate::allocator::{
+    COUNTERS_SIZE, MEM_ALLOCATED, MEM_ALLOCATED_CNT, MEM_CNT, MEM_FREED, MEM_FREED_CNT, MEM_PEAK,
+    MEM_SIZE,
+};
+use crate::histogram::{lifetime_histogram, size_histogram, Histogram};
+use crate::{live_set, scope, skip_cache_stats};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+
+/// Per-thread values aggregated over threads with the same name.
+#[derive(Debug, Default)]
+struct ThreadValues {
+    bytes: usize,
+    count: usize,
+    peak: usize,
+    allocated_bytes: usize,
+    allocated_count: usize,
+    freed_bytes: usize,
+    freed_count: usize,
+}
+
+/// Renders all proxy metrics in the OpenMetrics text format, including the trailing `# EOF`.
+#[must_use]
+pub fn render_openmetrics() -> String {
+    let names = thread_names();
+    let mut threads: BTreeMap<String, ThreadValues> = BTreeMap::new();
+    for idx in 0..COUNTERS_SIZE {
+        let allocated_count = MEM_ALLOCATED_CNT[idx].load(Ordering::Relaxed);
+        if allocated_count == 0 {
+            continue;
+        }
+        let name = names.get(&idx).cloned().unwrap_or_else(|| "exited".to_string());
+        let values = threads.entry(name).or_default();
+        values.bytes = values.bytes.wrapping_add(MEM_SIZE[idx].load(Ordering::Relaxed));
+        values.count = values.count.wrapping_add(MEM_CNT[idx].load(Ordering::Relaxed));
+        values.peak = values.peak.max(MEM_PEAK[idx].load(Ordering::Relaxed));
+        values.allocated_bytes += MEM_ALLOCATED[idx].load(Ordering::Relaxed);
+        values.allocated_count += allocated_count;
+        values.freed_bytes += MEM_FREED[idx].load(Ordering::Relaxed);
+        values.freed_count += MEM_FREED_CNT[idx].load(Ordering::Relaxed);
+    }
+
+    let mut out = String::new();
+    let total = |f: fn(&ThreadValues) -> usize| -> usize {
+        threads.values().map(f).fold(0, usize::wrapping_add)
+    };
+    family(&mut out, "allocator_proxy_memory_bytes", "gauge", "Memory allocated and not freed.");
+    sample(&mut out, "allocator_proxy_memory_bytes", "", total(|v| v.bytes));
+    family(&mut out, "allocator_proxy_allocations", "gauge", "Allocations not freed yet.");
+    sample(&mut out, "allocator_proxy_allocations", "", total(|v| v.count));
+    family(
+        &mut out,
+        "allocator_proxy_allocated_bytes",
+        "counter",
+        "Total size of all allocations ever made.",
+    );
+    sample(&mut out, "allocator_proxy_allocated_bytes_total", "", total(|v| v.allocated_bytes));
+    family(&mut out, "allocator_proxy_allocated", "counter", "Number of allocations ever made.");
+    sample(&mut out, "allocator_proxy_allocated_total", "", total(|v| v.allocated_count));
+    family(
+        &mut out,
+        "allocator_proxy_freed_bytes",
+        "counter",
+        "Total size of all allocations ever freed.",
+    );
+    sample(&mut out, "allocator_proxy_freed_bytes_total", "", total(|v| v.freed_bytes));
+    family(&mut out, "allocator_proxy_freed", "counter", "Number of allocations ever freed.");
+    sample(&mut out, "allocator_proxy_freed_total", "", total(|v| v.freed_count));
+    histogram(
+        &mut out,
+        "allocator_proxy_allocation_size_bytes",
+        "Sizes of all allocations.",
+        &size_histogram(),
+        1.0,
+    );
+    histogram(
+        &mut out,
+        "allocator_proxy_allocation_lifetime_seconds",
+        "Time between allocation and deallocation of allocations in the live registry.",
+        &lifetime_histogram(),
+        1e-9,
+    );
+
+    family(
+        &mut out,
+        "allocator_proxy_thread_memory_bytes",
+        "gauge",
+        "Memory allocated and not freed by threads with a given name.",
+    );
+    for (name, values) in &threads {
+        let labels = format!("thread=\"{}\"", escape(name));
+        sample(&mut out, "allocator_proxy_thread_memory_bytes", &labels, values.bytes);
+    }
+    family(
+        &mut out,
+        "allocator_proxy_thread_peak_memory_bytes",
+        "gauge",
+        "Highest memory usage of a single thread with a given name.",
+    );
+    for (name, values) in &threads {
+        let labels = format!("thread=\"{}\"", escape(name));
+        sample(&mut out, "allocator_proxy_thread_peak_memory_bytes", &labels, values.peak);
+    }
+    family(
+        &mut out,
+        "allocator_proxy_thread_allocated",
+        "counter",
+        "Number of allocations ever made by threads with a given name.",
+    );
+    for (name, values) in &threads {
+        let labels = format!("thread=\"{}\"", escape(name));
+        sample(&mut out, "allocator_proxy_thread_allocated_total", &labels, values.allocated_count);
+    }
+
+    family(
+        &mut out,
+        "allocator_proxy_tag_memory_bytes",
+        "gauge",
+        "Memory allocated and not freed with a given tag.",
+    );
+    for tag in 1..=u16::MAX {
+        let stats = scope::tag_totals(tag);
+        if stats.allocated_count != 0 {
+            let labels = format!("tag=\"{}\"", tag);
+            sample(&mut out, "allocator_proxy_tag_memory_bytes", &labels, stats.live_bytes());
+        }
+    }
+
+    let skip_cache = skip_cache_stats();
+    family(
+        &mut out,
+        "allocator_proxy_skip_cache_lookups",
+        "counter",
+        "Lookups of frames in the skip cache.",
+    );
+    sample(&mut out, "allocator_proxy_skip_cache_lookups_total", "result=\"hit\"", skip_cache.hits);
+    sample(
+        &mut out,
+        "allocator_proxy_skip_cache_lookups_total",
+        "result=\"miss\"",
+        skip_cache.misses,
+    );
+    family(
+        &mut out,
+        "allocator_proxy_symbol_resolutions",
+        "counter",
+        "Frames resolved to symbols to decide whether they should be skipped.",
+    );
+    sample(&mut out, "allocator_proxy_symbol_resolutions_total", "", skip_cache.resolves);
+    family(
+        &mut out,
+        "allocator_proxy_skip_cache_evictions",
+        "counter",
+        "Entries evicted from the skip cache.",
+    );
+    sample(&mut out, "allocator_proxy_skip_cache_evictions_total", "", skip_cache.evictions);
+    family(
+        &mut out,
+        "allocator_proxy_symbol_resolver_dropped",
+        "counter",
+        "Frames not queued for asynchronous symbol resolution, because the queue slot was taken.",
+    );
+    sample(
+        &mut out,
+        "allocator_proxy_symbol_resolver_dropped_total",
+        "",
+        skip_cache.resolver_dropped,
+    );
+
+    if live_set::USED.load(Ordering::Relaxed) {
+        let mut live = 0;
+        live_set::for_each_live_allocation(|_| live += 1);
+        family(
+            &mut out,
+            "allocator_proxy_live_sampled_allocations",
+            "gauge",
+            "Sampled allocations in the live allocation registry.",
+        );
+        sample(&mut out, "allocator_proxy_live_sampled_allocations", "", live);
+        family(
+            &mut out,
+            "allocator_proxy_live_dropped",
+            "counter",
+            "Sampled allocations, which didn't fit into the live allocation registry.",
+        );
+        sample(
+            &mut out,
+            "allocator_proxy_live_dropped_total",
+            "",
+            live_set::live_allocations_dropped(),
+        );
+    }
+
+    out.push_str("# EOF\n");
+    out
+}
+
+fn family(out: &mut String, name: &str, ty: &str, help: &str) {
+    let _ = writeln!(out, "# TYPE {} {}", name, ty);
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+}
+
+fn sample(out: &mut String, name: &str, labels: &str, value: impl std::fmt::Display) {
+    if labels.is_empty() {
+        let _ = writeln!(out, "{} {}", name, value);
+    } else {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+    }
+}
+
+/// Writes a histogram, bucket bounds and the sum are multiplied by `scale`.
+fn histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram, scale: f64) {
+    family(out, name, "histogram", help);
+    let bucket_name = format!("{}_bucket", name);
+    let mut cumulative = 0;
+    for (idx, count) in histogram.buckets.iter().enumerate() {
+        cumulative += count;
+        let le = match Histogram::upper_bound(idx) {
+            usize::MAX => "+Inf".to_string(),
+            bound => (bound as f64 * scale).to_string(),
+        };
+        sample(out, &bucket_name, &format!("le=\"{}\"", le), cumulative);
+    }
+    sample(out, &format!("{}_count", name), "", cumulative);
+    sample(out, &format!("{}_sum", name), "", histogram.sum as f64 * scale);
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Names of running threads by the index of their counters. Threads, which have exited, are
+/// missing.
+#[cfg(target_os = "linux")]
+fn thread_names() -> HashMap<usize, String> {
+    let mut names = HashMap::new();
+    for entry in std::fs::read_dir("/proc/self/task").into_iter().flatten().flatten() {
+        let tid = match entry.file_name().to_str().and_then(|tid| tid.parse::<usize>().ok()) {
+            Some(tid) => tid,
+            None => continue,
+        };
+        if let Ok(name) = std::fs::read_to_string(entry.path().join("comm")) {
+            names.insert(tid % COUNTERS_SIZE, name.trim_end().to_string());
+        }
+    }
+    names
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_names() -> HashMap<usize, String> {
+    (0..COUNTERS_SIZE).map(|idx| (idx, idx.to_string())).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::metrics::render_openmetrics;
+    use crate::{AllocScope, ProxyAllocator};
+    use std::alloc::{GlobalAlloc, Layout};
+
+    static ALLOC: ProxyAllocator<tikv_jemallocator::Jemalloc> =
+        ProxyAllocator::new(tikv_jemallocator::Jemalloc);
+
+    #[test]
+    fn test_render_openmetrics() {
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let scope = AllocScope::begin_tag(0x4d7);
+        let ptr = unsafe { ALLOC.alloc(layout) };
+
+        let metrics = render_openmetrics();
+        assert!(metrics.contains("\nallocator_proxy_tag_memory_bytes{tag=\"1239\"} 100\n"));
+        assert!(metrics.contains("# TYPE allocator_proxy_allocated counter\n"));
+        assert!(metrics.contains("\nallocator_proxy_allocation_size_bytes_bucket{le=\"+Inf\"} "));
+        assert!(metrics.ends_with("# EOF\n"));
+
+        unsafe { ALLOC.dealloc(ptr, layout) };
+        drop(scope);
+    }
+}
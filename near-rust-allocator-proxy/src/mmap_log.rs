@@ -0,0 +1,472 @@
+//! Log of `mmap` and `munmap` calls with the site, which made them.
+//!
+//! The last `MMAP_LOG_CAPACITY` calls are kept in a ring buffer. Old calls fall out of it, so the
+//! calls are also applied to a table of live mappings, which keeps long lived mappings with the
+//! site, which made them. Both live in their own anonymous mapping starting with
+//! `MMAP_LOG_MAGIC`, between two inaccessible pages, so that the kernel never merges it with other
+//! mappings. This lets `rust-memory-analyzer` find it in the memory of
+//! a process, a core dump or a heap dump and attribute anonymous mappings to the code, which
+//! created them.
+//!
+//! The proxy doesn't see `mmap` calls by itself. They are recorded by `near-c-allocator-proxy`,
+//! which interposes `mmap` and `munmap`, or by calling `record_mmap` and `record_munmap`.
+//!
+//! Slots and table entries are written like a seqlock, their sequence number is 0 while they are
+//! written. Updates of the table scan its used part, which is about as long as the number of live
+//! mappings recorded.
+use crate::allocator::{get_tid, trace_site, ENABLE_STACK_TRACE, IN_TRACE};
+use crate::histogram::now_nanos;
+use nix::libc;
+use std::mem::size_of;
+use std::os::raw::c_void;
+use std::ptr::null_mut;
+use std::sync::atomic::{fence, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Mutex, Once};
+
+/// Record calls passed to `record_mmap` and `record_munmap`.
+pub(crate) static MMAP_LOG: AtomicBool = AtomicBool::new(false);
+
+/// First word of the mapping holding the log, it reads `mmap_log` in ASCII in memory.
+pub const MMAP_LOG_MAGIC: usize = usize::from_ne_bytes(*b"mmap_log");
+/// Number of calls kept in the log.
+pub const MMAP_LOG_CAPACITY: usize = 1 << 16;
+/// Number of live mappings kept in the table, the default limit of mappings of a process
+/// (`vm.max_map_count`) is slightly lower.
+pub const MMAP_TABLE_CAPACITY: usize = 1 << 16;
+/// Number of bytes at the start of a log, which `mmap_log_size` needs.
+pub const MMAP_LOG_HEADER_SIZE: usize = size_of::<Header>();
+
+#[repr(C)]
+struct Header {
+    magic: usize,
+    capacity: usize,
+    /// Number of calls recorded so far.
+    next: AtomicUsize,
+    table_capacity: usize,
+    /// Number of table entries, which were used, the ones after them are free.
+    table_used: AtomicUsize,
+    /// Mappings, which didn't fit into the table.
+    dropped: AtomicUsize,
+}
+
+#[repr(C)]
+struct Slot {
+    /// Number of calls recorded before this one plus 1, 0 while the slot is being written.
+    seq: AtomicUsize,
+    kind: AtomicUsize,
+    address: AtomicUsize,
+    len: AtomicUsize,
+    site: AtomicUsize,
+    tid: AtomicUsize,
+    time: AtomicUsize,
+}
+
+/// Live mapping in the table, free if `end` is 0.
+#[repr(C)]
+struct Entry {
+    /// Sequence number of the call, which wrote the entry last, 0 while it's written or free.
+    seq: AtomicUsize,
+    start: AtomicUsize,
+    end: AtomicUsize,
+    site: AtomicUsize,
+    tid: AtomicUsize,
+    time: AtomicUsize,
+}
+
+#[repr(C)]
+struct Log {
+    header: Header,
+    slots: [Slot; MMAP_LOG_CAPACITY],
+    table: [Entry; MMAP_TABLE_CAPACITY],
+}
+
+static LOG: AtomicPtr<Log> = AtomicPtr::new(null_mut());
+static CREATE: Once = Once::new();
+/// Taken while updating the table.
+static TABLE: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MmapKind {
+    Map = 1,
+    Unmap = 2,
+}
+
+/// Call recorded in the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MmapEvent {
+    pub kind: MmapKind,
+    pub address: usize,
+    pub len: usize,
+    /// Frame the call is attributed to, null if stack traces are disabled.
+    pub site: *mut c_void,
+    pub tid: usize,
+    /// Nanoseconds since an arbitrary point in the past, comparable between logs of one process.
+    pub time: usize,
+}
+
+/// Mapping in the table of live mappings, parts of it may have been unmapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LiveMapping {
+    pub start: usize,
+    pub end: usize,
+    /// Frame the mapping is attributed to, null if stack traces are disabled.
+    pub site: *mut c_void,
+    pub tid: usize,
+    /// Time of the call, which mapped it, see `MmapEvent::time`.
+    pub time: usize,
+}
+
+/// Creates the log, once.
+pub(crate) fn create() {
+    CREATE.call_once(|| LOG.store(unsafe { map_log() }, Ordering::Release));
+}
+
+/// Maps the log between two inaccessible pages, null if that fails.
+unsafe fn map_log() -> *mut Log {
+    let page_size = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+    let len = (size_of::<Log>() + page_size - 1) / page_size * page_size;
+    // A raw syscall, so that `mmap` interposed by `near-c-allocator-proxy` doesn't record it.
+    let block = libc::syscall(
+        libc::SYS_mmap,
+        0 as libc::c_long,
+        (len + 2 * page_size) as libc::c_long,
+        libc::PROT_NONE as libc::c_long,
+        (libc::MAP_PRIVATE | libc::MAP_ANONYMOUS) as libc::c_long,
+        -1 as libc::c_long,
+        0 as libc::c_long,
+    );
+    if block == -1 {
+        return null_mut();
+    }
+    let log = (block as usize + page_size) as *mut Log;
+    if libc::mprotect(log.cast(), len, libc::PROT_READ | libc::PROT_WRITE) != 0 {
+        return null_mut();
+    }
+    (*log).header.magic = MMAP_LOG_MAGIC;
+    (*log).header.capacity = MMAP_LOG_CAPACITY;
+    (*log).header.table_capacity = MMAP_TABLE_CAPACITY;
+    log
+}
+
+/// Records that `len` bytes were mapped at `address`, if enabled with
+/// `ProxyAllocator::enable_mmap_log`. Only anonymous mappings need to be recorded.
+pub fn record_mmap(address: *mut c_void, len: usize) {
+    record(MmapKind::Map, address as usize, len);
+}
+
+/// Records that `len` bytes were unmapped at `address`, if enabled with
+/// `ProxyAllocator::enable_mmap_log`.
+pub fn record_munmap(address: *mut c_void, len: usize) {
+    record(MmapKind::Unmap, address as usize, len);
+}
+
+fn record(kind: MmapKind, address: usize, len: usize) {
+    if !MMAP_LOG.load(Ordering::Relaxed) {
+        return;
+    }
+    let log = LOG.load(Ordering::Acquire);
+    if log.is_null() {
+        return;
+    }
+    let log = unsafe { &*log };
+    let site = if ENABLE_STACK_TRACE.load(Ordering::Relaxed) {
+        IN_TRACE.with(|in_trace| {
+            if in_trace.replace(1) != 0 {
+                // Mapping made while computing a stack trace.
+                return null_mut();
+            }
+            let site = unsafe { trace_site() };
+            in_trace.set(0);
+            site
+        })
+    } else {
+        null_mut()
+    };
+
+    let (tid, time) = (get_tid(), now_nanos());
+    let seq = log.header.next.fetch_add(1, Ordering::Relaxed);
+    let slot = &log.slots[seq % MMAP_LOG_CAPACITY];
+    slot.seq.store(0, Ordering::Relaxed);
+    fence(Ordering::Release);
+    slot.kind.store(kind as usize, Ordering::Relaxed);
+    slot.address.store(address, Ordering::Relaxed);
+    slot.len.store(len, Ordering::Relaxed);
+    slot.site.store(site as usize, Ordering::Relaxed);
+    slot.tid.store(tid, Ordering::Relaxed);
+    slot.time.store(time, Ordering::Relaxed);
+    slot.seq.store(seq + 1, Ordering::Release);
+
+    let call = LiveMapping { start: address, end: address.saturating_add(len), site, tid, time };
+    if call.end > call.start {
+        update_table(log, kind, call, seq + 1);
+    }
+}
+
+/// Applies a call to the table, `call` is the range it maps or unmaps.
+fn update_table(log: &Log, kind: MmapKind, call: LiveMapping, seq: usize) {
+    let _lock = TABLE.lock().unwrap_or_else(|err| err.into_inner());
+    let used = log.header.table_used.load(Ordering::Relaxed);
+    // Mapping over an existing mapping replaces it, like `MAP_FIXED` does.
+    for entry in &log.table[..used] {
+        let mapping = read_entry(entry);
+        if mapping.end == 0 || mapping.end <= call.start || mapping.start >= call.end {
+            continue;
+        }
+        if mapping.start < call.start {
+            write_entry(entry, LiveMapping { end: call.start, ..mapping }, seq);
+            if mapping.end > call.end {
+                insert_entry(log, LiveMapping { start: call.end, ..mapping }, seq);
+            }
+        } else if mapping.end > call.end {
+            write_entry(entry, LiveMapping { start: call.end, ..mapping }, seq);
+        } else {
+            entry.seq.store(0, Ordering::Release);
+            entry.end.store(0, Ordering::Relaxed);
+        }
+    }
+    if kind == MmapKind::Map {
+        insert_entry(log, call, seq);
+    }
+    let mut used = log.header.table_used.load(Ordering::Relaxed);
+    while used > 0 && log.table[used - 1].end.load(Ordering::Relaxed) == 0 {
+        used -= 1;
+    }
+    log.header.table_used.store(used, Ordering::Release);
+}
+
+fn read_entry(entry: &Entry) -> LiveMapping {
+    LiveMapping {
+        start: entry.start.load(Ordering::Relaxed),
+        end: entry.end.load(Ordering::Relaxed),
+        site: entry.site.load(Ordering::Relaxed) as *mut c_void,
+        tid: entry.tid.load(Ordering::Relaxed),
+        time: entry.time.load(Ordering::Relaxed),
+    }
+}
+
+fn write_entry(entry: &Entry, mapping: LiveMapping, seq: usize) {
+    entry.seq.store(0, Ordering::Relaxed);
+    fence(Ordering::Release);
+    entry.start.store(mapping.start, Ordering::Relaxed);
+    entry.end.store(mapping.end, Ordering::Relaxed);
+    entry.site.store(mapping.site as usize, Ordering::Relaxed);
+    entry.tid.store(mapping.tid, Ordering::Relaxed);
+    entry.time.store(mapping.time, Ordering::Relaxed);
+    entry.seq.store(seq, Ordering::Release);
+}
+
+/// Writes `mapping` to the first free entry of the table, counts it as dropped if it's full.
+fn insert_entry(log: &Log, mapping: LiveMapping, seq: usize) {
+    let used = log.header.table_used.load(Ordering::Relaxed);
+    let free = log.table[..used].iter().find(|entry| entry.end.load(Ordering::Relaxed) == 0);
+    if let Some(entry) = free {
+        write_entry(entry, mapping, seq);
+    } else if used < MMAP_TABLE_CAPACITY {
+        write_entry(&log.table[used], mapping, seq);
+        log.header.table_used.store(used + 1, Ordering::Release);
+    } else {
+        log.header.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn words(bytes: &[u8]) -> impl Iterator<Item = usize> + '_ {
+    bytes
+        .chunks_exact(size_of::<usize>())
+        .map(|word| usize::from_ne_bytes(word.try_into().unwrap()))
+}
+
+/// Number of bytes at the start of a log, which hold recorded calls and the used part of the
+/// table, if `header` is the start of a log. `header` has to be at least `MMAP_LOG_HEADER_SIZE`
+/// long.
+#[must_use]
+pub fn mmap_log_size(header: &[u8]) -> Option<usize> {
+    let header: Vec<usize> = words(header).take(6).collect();
+    let &[magic, capacity, _, table_capacity, table_used, _] = header.as_slice() else {
+        return None;
+    };
+    if magic != MMAP_LOG_MAGIC || capacity == 0 || table_used > table_capacity {
+        return None;
+    }
+    let slots = capacity.checked_mul(size_of::<Slot>())?;
+    let table = table_used.checked_mul(size_of::<Entry>())?;
+    MMAP_LOG_HEADER_SIZE.checked_add(slots)?.checked_add(table)
+}
+
+/// Words of up to `count` records of `T` in `log` starting at `offset`.
+fn records<T>(log: &[u8], offset: usize, count: usize) -> impl Iterator<Item = Vec<usize>> + '_ {
+    let records = log.get(offset.min(log.len())..).unwrap_or_default();
+    records.chunks_exact(size_of::<T>()).take(count).map(|record| words(record).collect())
+}
+
+/// Calls recorded in a copy of the first `mmap_log_size` bytes of a log, oldest first, and the
+/// number of older calls, which were overwritten. `again` is a second copy made right after `log`.
+/// Slots are only used if their sequence number is the same in both copies, otherwise they were
+/// being recorded while `log` was copied.
+#[must_use]
+pub fn parse_mmap_log(log: &[u8], again: &[u8]) -> (Vec<MmapEvent>, usize) {
+    let header: Vec<usize> = words(&log[..MMAP_LOG_HEADER_SIZE.min(log.len())]).collect();
+    if header.len() < 3 {
+        return (Vec::new(), 0);
+    }
+    let (capacity, next) = (header[1], header[2]);
+    let slots = records::<Slot>(log, MMAP_LOG_HEADER_SIZE, capacity);
+    let slots_again = records::<Slot>(again, MMAP_LOG_HEADER_SIZE, capacity);
+    let mut events: Vec<(usize, MmapEvent)> = (slots.zip(slots_again))
+        .filter_map(|(slot, slot_again)| {
+            if slot[0] == 0 || slot[0] != slot_again[0] {
+                return None;
+            }
+            let kind = match slot[1] {
+                1 => MmapKind::Map,
+                2 => MmapKind::Unmap,
+                _ => return None,
+            };
+            let event = MmapEvent {
+                kind,
+                address: slot[2],
+                len: slot[3],
+                site: slot[4] as *mut c_void,
+                tid: slot[5],
+                time: slot[6],
+            };
+            Some((slot[0], event))
+        })
+        .collect();
+    events.sort_unstable_by_key(|(seq, _)| *seq);
+    (events.into_iter().map(|(_, event)| event).collect(), next.saturating_sub(capacity))
+}
+
+/// Mappings in the table of a log in the same copies as for `parse_mmap_log` and the number of
+/// mappings, which didn't fit into the table. Entries are only used if their sequence number is
+/// the same in both copies.
+#[must_use]
+pub fn parse_live_mappings(log: &[u8], again: &[u8]) -> (Vec<LiveMapping>, usize) {
+    let header: Vec<usize> = words(&log[..MMAP_LOG_HEADER_SIZE.min(log.len())]).collect();
+    if header.len() < 6 {
+        return (Vec::new(), 0);
+    }
+    let (capacity, dropped) = (header[1], header[5]);
+    let offset = capacity.saturating_mul(size_of::<Slot>()).saturating_add(MMAP_LOG_HEADER_SIZE);
+    let entries = records::<Entry>(log, offset, usize::MAX);
+    let entries_again = records::<Entry>(again, offset, usize::MAX);
+    let mappings = (entries.zip(entries_again))
+        .filter(|(entry, entry_again)| entry[0] != 0 && entry[0] == entry_again[0])
+        .map(|(entry, _)| LiveMapping {
+            start: entry[1],
+            end: entry[2],
+            site: entry[3] as *mut c_void,
+            tid: entry[4],
+            time: entry[5],
+        })
+        .filter(|mapping| mapping.end > mapping.start)
+        .collect();
+    (mappings, dropped)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mmap_log::{Entry, Slot, LOG, MMAP_LOG_CAPACITY, MMAP_LOG_HEADER_SIZE};
+    use crate::{get_tid, mmap_log_size, parse_live_mappings, parse_mmap_log};
+    use crate::{record_mmap, record_munmap, LiveMapping, MmapKind, ProxyAllocator};
+    use std::mem::size_of;
+    use std::os::raw::c_void;
+    use std::sync::atomic::Ordering;
+
+    static ALLOC: ProxyAllocator<tikv_jemallocator::Jemalloc> =
+        ProxyAllocator::new(tikv_jemallocator::Jemalloc);
+
+    /// Copy of the recorded part of the log.
+    fn read_log() -> Vec<u8> {
+        let log = LOG.load(Ordering::Acquire).cast::<u8>();
+        let header = unsafe { std::slice::from_raw_parts(log, MMAP_LOG_HEADER_SIZE) };
+        let size = mmap_log_size(header).unwrap();
+        unsafe { std::slice::from_raw_parts(log, size) }.to_vec()
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_mmap_log() {
+        let address = 0x1234_5000 as *mut c_void;
+        ALLOC.enable_mmap_log(true);
+        record_mmap(address, 1 << 20);
+        record_munmap(address, 4096);
+        ALLOC.enable_mmap_log(false);
+        record_mmap(address, 1 << 20);
+
+        let log = read_log();
+        let (events, overwritten) = parse_mmap_log(&log, &log);
+        assert_eq!(overwritten, 0);
+        let [map, unmap] = [events[events.len() - 2], events[events.len() - 1]];
+        assert_eq!((map.kind, map.address, map.len), (MmapKind::Map, address as usize, 1 << 20));
+        assert_eq!(
+            (unmap.kind, unmap.address, unmap.len),
+            (MmapKind::Unmap, address as usize, 4096)
+        );
+        assert_eq!(map.tid, get_tid());
+        assert!(map.time <= unmap.time);
+        assert_eq!(mmap_log_size(&[0; MMAP_LOG_HEADER_SIZE]), None);
+        assert_eq!(&log[..8], b"mmap_log");
+
+        // The last slot was overwritten between both copies, so it may be torn.
+        let mut again = log.clone();
+        let seq = MMAP_LOG_HEADER_SIZE + (events.len() - 1) * size_of::<Slot>();
+        again[seq..seq + 8].copy_from_slice(&0usize.to_ne_bytes());
+        let (torn, _) = parse_mmap_log(&log, &again);
+        assert_eq!(torn, events[..events.len() - 1]);
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_live_mappings() {
+        let base = 0x7e00_0000_0000_usize;
+        let mapping = |start, len| (base + start, base + start + len);
+        let at = |offset: usize| (base + offset) as *mut c_void;
+        ALLOC.enable_mmap_log(true);
+        record_mmap(at(0x10000), 0x10000);
+        record_mmap(at(0x30000), 0x1000);
+        // Unmapping the middle of a mapping splits it.
+        record_munmap(at(0x14000), 0x2000);
+        // Mapping over a mapping replaces that part of it.
+        record_mmap(at(0x18000), 0x1000);
+        record_munmap(at(0x30000), 0x1000);
+        ALLOC.enable_mmap_log(false);
+
+        let log = read_log();
+        let (mappings, dropped) = parse_live_mappings(&log, &log);
+        assert_eq!(dropped, 0);
+        let mut mappings: Vec<LiveMapping> =
+            mappings.into_iter().filter(|mapping| mapping.start >= base).collect();
+        mappings.sort_by_key(|mapping| mapping.start);
+        assert_eq!(
+            mappings.iter().map(|mapping| (mapping.start, mapping.end)).collect::<Vec<_>>(),
+            [
+                mapping(0x10000, 0x4000),
+                mapping(0x16000, 0x2000),
+                mapping(0x18000, 0x1000),
+                mapping(0x19000, 0x7000)
+            ]
+        );
+        // Parts of a mapping keep the time it was made.
+        assert_eq!(mappings[0].time, mappings[3].time);
+        assert!(mappings[0].time <= mappings[2].time);
+        assert!(mappings.iter().all(|mapping| mapping.tid == get_tid()));
+
+        // Entries of the mappings above were rewritten between both copies.
+        let mut again = log.clone();
+        let table = MMAP_LOG_HEADER_SIZE + MMAP_LOG_CAPACITY * size_of::<Slot>();
+        for entry in again[table..].chunks_exact_mut(size_of::<Entry>()) {
+            if usize::from_ne_bytes(entry[8..16].try_into().unwrap()) >= base {
+                entry[..8].copy_from_slice(&usize::MAX.to_ne_bytes());
+            }
+        }
+        let (torn, _) = parse_live_mappings(&log, &again);
+        assert!(torn.iter().all(|mapping| mapping.start < base));
+
+        ALLOC.enable_mmap_log(true);
+        record_munmap(base as *mut c_void, 0x20000);
+        ALLOC.enable_mmap_log(false);
+        let log = read_log();
+        let (mappings, _) = parse_live_mappings(&log, &log);
+        assert!(mappings.iter().all(|mapping| mapping.start < base));
+    }
+}
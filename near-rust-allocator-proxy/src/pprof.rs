@@ -0,0 +1,295 @@
+//! Minimal encoder of pprof profiles.
+//!
+//! See <https://github.com/google/pprof/blob/main/proto/profile.proto> for the format. Only the
+//! subset needed to describe memory profiles is supported.
+use crate::allocator::scale_sample;
+use crate::{live_set, ProxyAllocator};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::os::raw::c_void;
+
+/// Memory region of the profiled process, which locations can point into.
+#[derive(Debug, Default, Clone)]
+pub struct Mapping {
+    pub memory_start: u64,
+    pub memory_limit: u64,
+    pub file_offset: u64,
+    pub filename: String,
+    pub build_id: String,
+}
+
+/// Builds a pprof `Profile` message.
+#[derive(Debug)]
+pub struct ProfileBuilder {
+    strings: Vec<String>,
+    string_ids: HashMap<String, i64>,
+    sample_types: Vec<(i64, i64)>,
+    default_sample_type: i64,
+    comments: Vec<i64>,
+    samples: Vec<u8>,
+    mappings: Vec<u8>,
+    mappings_len: u64,
+    locations: Vec<u8>,
+    location_ids: HashMap<(u64, u64), u64>,
+    functions: Vec<u8>,
+    function_ids: HashMap<String, u64>,
+}
+
+impl ProfileBuilder {
+    /// Creates a profile with given `(type, unit)` pairs of sample values, e.g.
+    /// `("inuse_space", "bytes")`. The last one is shown by default.
+    pub fn new(sample_types: &[(&str, &str)]) -> Self {
+        let mut builder = Self {
+            // String table always starts with an empty string.
+            strings: vec![String::new()],
+            string_ids: HashMap::from([(String::new(), 0)]),
+            sample_types: Vec::new(),
+            default_sample_type: 0,
+            comments: Vec::new(),
+            samples: Vec::new(),
+            mappings: Vec::new(),
+            mappings_len: 0,
+            locations: Vec::new(),
+            location_ids: HashMap::new(),
+            functions: Vec::new(),
+            function_ids: HashMap::new(),
+        };
+        for (ty, unit) in sample_types {
+            let ty = builder.string_id(ty);
+            let unit = builder.string_id(unit);
+            builder.sample_types.push((ty, unit));
+            builder.default_sample_type = ty;
+        }
+        builder
+    }
+
+    fn string_id(&mut self, s: &str) -> i64 {
+        if let Some(id) = self.string_ids.get(s) {
+            return *id;
+        }
+        let id = self.strings.len() as i64;
+        self.strings.push(s.to_string());
+        self.string_ids.insert(s.to_string(), id);
+        id
+    }
+
+    /// Adds a mapping and returns its id.
+    pub fn add_mapping(&mut self, mapping: &Mapping) -> u64 {
+        self.mappings_len += 1;
+        let id = self.mappings_len;
+        let filename = self.string_id(&mapping.filename);
+        let build_id = self.string_id(&mapping.build_id);
+        let mut msg = Vec::new();
+        encode_uint(&mut msg, 1, id);
+        encode_uint(&mut msg, 2, mapping.memory_start);
+        encode_uint(&mut msg, 3, mapping.memory_limit);
+        encode_uint(&mut msg, 4, mapping.file_offset);
+        encode_uint(&mut msg, 5, filename as u64);
+        encode_uint(&mut msg, 6, build_id as u64);
+        encode_uint(&mut msg, 7, 1);
+        encode_bytes(&mut self.mappings, 3, &msg);
+        id
+    }
+
+    fn function_id(&mut self, name: &str) -> u64 {
+        if let Some(id) = self.function_ids.get(name) {
+            return *id;
+        }
+        let id = self.function_ids.len() as u64 + 1;
+        let name_id = self.string_id(name);
+        let mut msg = Vec::new();
+        encode_uint(&mut msg, 1, id);
+        encode_uint(&mut msg, 2, name_id as u64);
+        encode_uint(&mut msg, 3, name_id as u64);
+        encode_bytes(&mut self.functions, 5, &msg);
+        self.function_ids.insert(name.to_string(), id);
+        id
+    }
+
+    /// Returns id of the location at `address` inside mapping `mapping_id` (`0` if unknown),
+    /// adding it if needed. `function` is the name of the function the address belongs to.
+    pub fn location(&mut self, address: u64, mapping_id: u64, function: Option<&str>) -> u64 {
+        if let Some(id) = self.location_ids.get(&(address, mapping_id)) {
+            return *id;
+        }
+        let id = self.location_ids.len() as u64 + 1;
+        let mut msg = Vec::new();
+        encode_uint(&mut msg, 1, id);
+        encode_uint(&mut msg, 2, mapping_id);
+        encode_uint(&mut msg, 3, address);
+        if let Some(function) = function {
+            let function_id = self.function_id(function);
+            let mut line = Vec::new();
+            encode_uint(&mut line, 1, function_id);
+            encode_bytes(&mut msg, 4, &line);
+        }
+        encode_bytes(&mut self.locations, 4, &msg);
+        self.location_ids.insert((address, mapping_id), id);
+        id
+    }
+
+    /// Adds a sample. `locations` go from the leaf to the root of the stack, `values` follow the
+    /// order of sample types passed to `new`.
+    pub fn add_sample(&mut self, locations: &[u64], values: &[i64], labels: &[(&str, &str)]) {
+        let mut msg = Vec::new();
+        encode_packed(&mut msg, 1, locations.iter().copied());
+        encode_packed(&mut msg, 2, values.iter().map(|&v| v as u64));
+        for (key, value) in labels {
+            let key = self.string_id(key);
+            let value = self.string_id(value);
+            let mut label = Vec::new();
+            encode_uint(&mut label, 1, key as u64);
+            encode_uint(&mut label, 2, value as u64);
+            encode_bytes(&mut msg, 3, &label);
+        }
+        encode_bytes(&mut self.samples, 2, &msg);
+    }
+
+    /// Adds a free-form comment, which `pprof` shows in the profile header.
+    pub fn add_comment(&mut self, comment: &str) {
+        let id = self.string_id(comment);
+        self.comments.push(id);
+    }
+
+    /// Serializes the profile into an uncompressed `Profile` message.
+    #[must_use]
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (ty, unit) in &self.sample_types {
+            let mut msg = Vec::new();
+            encode_uint(&mut msg, 1, *ty as u64);
+            encode_uint(&mut msg, 2, *unit as u64);
+            encode_bytes(&mut out, 1, &msg);
+        }
+        out.extend_from_slice(&self.samples);
+        out.extend_from_slice(&self.mappings);
+        out.extend_from_slice(&self.locations);
+        out.extend_from_slice(&self.functions);
+        for s in &self.strings {
+            encode_bytes(&mut out, 6, s.as_bytes());
+        }
+        encode_packed(&mut out, 13, self.comments.iter().map(|&c| c as u64));
+        encode_uint(&mut out, 14, self.default_sample_type as u64);
+        out
+    }
+
+    /// Writes the gzip compressed profile, which is the format `pprof` expects.
+    pub fn write_gzip(&self, writer: impl Write) -> io::Result<()> {
+        let mut encoder = GzEncoder::new(writer, Compression::default());
+        encoder.write_all(&self.encode())?;
+        encoder.finish()?;
+        Ok(())
+    }
+}
+
+fn encode_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn encode_uint(out: &mut Vec<u8>, field: u32, value: u64) {
+    if value != 0 {
+        encode_varint(out, u64::from(field) << 3);
+        encode_varint(out, value);
+    }
+}
+
+fn encode_bytes(out: &mut Vec<u8>, field: u32, value: &[u8]) {
+    encode_varint(out, u64::from(field) << 3 | 2);
+    encode_varint(out, value.len() as u64);
+    out.extend_from_slice(value);
+}
+
+fn encode_packed(out: &mut Vec<u8>, field: u32, values: impl Iterator<Item = u64>) {
+    let mut packed = Vec::new();
+    for value in values {
+        encode_varint(&mut packed, value);
+    }
+    if !packed.is_empty() {
+        encode_bytes(out, field, &packed);
+    }
+}
+
+impl<A> ProxyAllocator<A> {
+    /// Returns a gzip compressed pprof heap profile of sampled allocations.
+    ///
+    /// Requires live tracking to be enabled with `enable_live_tracking`. Values are scaled to
+    /// account for sampling of small allocations.
+    pub fn heap_profile(&self) -> io::Result<Vec<u8>> {
+        // [alloc_objects, alloc_space, inuse_objects, inuse_space] per site.
+        let mut sites: HashMap<*mut c_void, [usize; 4]> = HashMap::new();
+        live_set::for_each_site(|site, count, size| {
+            let values = sites.entry(site).or_default();
+            values[0] += count;
+            values[1] += size;
+        });
+        live_set::for_each_live_allocation(|record| {
+            let values = sites.entry(record.site).or_default();
+            values[2] += scale_sample(record.size, 1);
+            values[3] += scale_sample(record.size, record.size);
+        });
+
+        let mut builder = ProfileBuilder::new(&[
+            ("alloc_objects", "count"),
+            ("alloc_space", "bytes"),
+            ("inuse_objects", "count"),
+            ("inuse_space", "bytes"),
+        ]);
+        for (site, values) in sites {
+            let mut name = None;
+            backtrace::resolve(site, |symbol| {
+                if name.is_none() {
+                    name = symbol.name().map(|name| name.to_string());
+                }
+            });
+            let location =
+                builder.location(site as u64, 0, Some(name.as_deref().unwrap_or("[unknown]")));
+            builder.add_sample(&[location], &values.map(|v| v as i64), &[]);
+        }
+        let dropped = live_set::live_allocations_dropped();
+        if dropped > 0 {
+            builder
+                .add_comment(&format!("{} sampled allocations didn't fit the registry", dropped));
+        }
+
+        let mut out = Vec::new();
+        builder.write_gzip(&mut out)?;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ProxyAllocator;
+    use flate2::read::GzDecoder;
+    use std::alloc::{GlobalAlloc, Layout};
+    use std::io::Read;
+
+    static ALLOC: ProxyAllocator<tikv_jemallocator::Jemalloc> =
+        ProxyAllocator::new(tikv_jemallocator::Jemalloc);
+
+    #[test]
+    #[serial_test::serial]
+    fn test_heap_profile() {
+        ALLOC.enable_stack_trace(true).enable_live_tracking(true);
+        let layout = Layout::from_size_align(4096, 8).unwrap();
+        let ptrs: Vec<_> = (0..10).map(|_| unsafe { ALLOC.alloc(layout) }).collect();
+
+        let profile = ALLOC.heap_profile().unwrap();
+        let mut decoded = Vec::new();
+        GzDecoder::new(profile.as_slice()).read_to_end(&mut decoded).unwrap();
+        assert!(String::from_utf8_lossy(&decoded).contains("inuse_space"));
+        // Sample values end with `inuse_objects` = 10 and `inuse_space` = 40960 as varints.
+        assert!(decoded.windows(4).any(|w| w == [10, 0x80, 0xc0, 0x02]));
+
+        for ptr in ptrs {
+            unsafe { ALLOC.dealloc(ptr, layout) };
+        }
+        ALLOC.enable_stack_trace(false).enable_live_tracking(false);
+    }
+}
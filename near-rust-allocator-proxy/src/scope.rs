@@ -0,0 +1,281 @@
+//! Measuring allocations made within a scope, mostly for use in tests.
+//!
+//! Counters of the current thread are thread local, so tests using them don't interfere with each
+//! other and don't need to be run serially. Counters of tags are shared between threads.
+use crate::allocator::{current_thread_tag, set_current_thread_tag};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// One set of counters per tag.
+const TAG_COUNTERS_SIZE: usize = 1 << u16::BITS;
+// SAFETY (for all transmutes below): `usize` and `AtomicUsize` have the same representation.
+static TAG_ALLOCATED: [AtomicUsize; TAG_COUNTERS_SIZE] = unsafe {
+    std::mem::transmute::<[usize; TAG_COUNTERS_SIZE], [AtomicUsize; TAG_COUNTERS_SIZE]>(
+        [0_usize; TAG_COUNTERS_SIZE],
+    )
+};
+static TAG_ALLOCATED_CNT: [AtomicUsize; TAG_COUNTERS_SIZE] = unsafe {
+    std::mem::transmute::<[usize; TAG_COUNTERS_SIZE], [AtomicUsize; TAG_COUNTERS_SIZE]>(
+        [0_usize; TAG_COUNTERS_SIZE],
+    )
+};
+static TAG_FREED: [AtomicUsize; TAG_COUNTERS_SIZE] = unsafe {
+    std::mem::transmute::<[usize; TAG_COUNTERS_SIZE], [AtomicUsize; TAG_COUNTERS_SIZE]>(
+        [0_usize; TAG_COUNTERS_SIZE],
+    )
+};
+static TAG_FREED_CNT: [AtomicUsize; TAG_COUNTERS_SIZE] = unsafe {
+    std::mem::transmute::<[usize; TAG_COUNTERS_SIZE], [AtomicUsize; TAG_COUNTERS_SIZE]>(
+        [0_usize; TAG_COUNTERS_SIZE],
+    )
+};
+
+thread_local! {
+    static THREAD_ALLOCATED: Cell<usize> = Cell::new(0);
+    static THREAD_ALLOCATED_CNT: Cell<usize> = Cell::new(0);
+    static THREAD_FREED: Cell<usize> = Cell::new(0);
+    static THREAD_FREED_CNT: Cell<usize> = Cell::new(0);
+}
+
+fn add(cell: &'static std::thread::LocalKey<Cell<usize>>, value: usize) {
+    cell.with(|c| c.set(c.get().wrapping_add(value)));
+}
+
+/// Called by `alloc` with the size and tag of a new allocation.
+pub(crate) fn record_alloc(size: usize, tag: u16) {
+    add(&THREAD_ALLOCATED, size);
+    add(&THREAD_ALLOCATED_CNT, 1);
+    if tag != 0 {
+        let idx = usize::from(tag);
+        TAG_ALLOCATED[idx].fetch_add(size, Ordering::Relaxed);
+        TAG_ALLOCATED_CNT[idx].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Called by `dealloc` with the size and tag of the freed allocation.
+pub(crate) fn record_free(size: usize, tag: u16) {
+    add(&THREAD_FREED, size);
+    add(&THREAD_FREED_CNT, 1);
+    if tag != 0 {
+        let idx = usize::from(tag);
+        TAG_FREED[idx].fetch_add(size, Ordering::Relaxed);
+        TAG_FREED_CNT[idx].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Allocations and frees counted by an `AllocScope`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AllocStats {
+    pub allocated_bytes: usize,
+    pub allocated_count: usize,
+    pub freed_bytes: usize,
+    pub freed_count: usize,
+}
+
+impl AllocStats {
+    /// Bytes allocated and not freed. Negative if more memory was freed than allocated, e.g.
+    /// because memory allocated before the scope began was freed within it.
+    #[must_use]
+    pub fn live_bytes(&self) -> isize {
+        self.allocated_bytes.wrapping_sub(self.freed_bytes) as isize
+    }
+
+    /// Number of allocations, which were not freed. Can be negative, like `live_bytes`.
+    #[must_use]
+    pub fn live_count(&self) -> isize {
+        self.allocated_count.wrapping_sub(self.freed_count) as isize
+    }
+
+    fn since(&self, start: &Self) -> Self {
+        Self {
+            allocated_bytes: self.allocated_bytes.wrapping_sub(start.allocated_bytes),
+            allocated_count: self.allocated_count.wrapping_sub(start.allocated_count),
+            freed_bytes: self.freed_bytes.wrapping_sub(start.freed_bytes),
+            freed_count: self.freed_count.wrapping_sub(start.freed_count),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    Thread,
+    Tag { tag: u16, previous: u16 },
+}
+
+impl Target {
+    fn totals(self) -> AllocStats {
+        match self {
+            Target::Thread => AllocStats {
+                allocated_bytes: THREAD_ALLOCATED.with(Cell::get),
+                allocated_count: THREAD_ALLOCATED_CNT.with(Cell::get),
+                freed_bytes: THREAD_FREED.with(Cell::get),
+                freed_count: THREAD_FREED_CNT.with(Cell::get),
+            },
+            Target::Tag { tag, .. } => tag_totals(tag),
+        }
+    }
+}
+
+/// Allocations and frees with `tag` since the start of the process.
+pub(crate) fn tag_totals(tag: u16) -> AllocStats {
+    let idx = usize::from(tag);
+    AllocStats {
+        allocated_bytes: TAG_ALLOCATED[idx].load(Ordering::Relaxed),
+        allocated_count: TAG_ALLOCATED_CNT[idx].load(Ordering::Relaxed),
+        freed_bytes: TAG_FREED[idx].load(Ordering::Relaxed),
+        freed_count: TAG_FREED_CNT[idx].load(Ordering::Relaxed),
+    }
+}
+
+/// Guard measuring allocations made through `ProxyAllocator` since it was created.
+///
+/// ```ignore
+/// let scope = AllocScope::begin();
+/// let v = vec![0_u8; 100];
+/// assert_max_allocated_bytes!(scope, 100);
+/// drop(v);
+/// assert_no_leaks!(scope);
+/// ```
+#[derive(Debug)]
+#[must_use]
+pub struct AllocScope {
+    target: Target,
+    start: AllocStats,
+}
+
+impl AllocScope {
+    /// Measures allocations made and freed by the current thread.
+    pub fn begin() -> Self {
+        let target = Target::Thread;
+        Self { target, start: target.totals() }
+    }
+
+    /// Tags the current thread with `tag` until the scope is dropped and measures allocations
+    /// with this tag, including ones made and freed by other threads.
+    /// Tag `0` means no tag and can't be used.
+    pub fn begin_tag(tag: u16) -> Self {
+        assert_ne!(tag, 0, "tag 0 means no tag");
+        let previous = set_current_thread_tag(tag);
+        let target = Target::Tag { tag, previous };
+        Self { target, start: target.totals() }
+    }
+
+    /// Returns allocations and frees counted since the scope began.
+    #[must_use]
+    pub fn stats(&self) -> AllocStats {
+        self.target.totals().since(&self.start)
+    }
+
+    /// Ends the scope and returns what was counted.
+    #[must_use]
+    pub fn end(self) -> AllocStats {
+        self.stats()
+    }
+}
+
+impl Drop for AllocScope {
+    fn drop(&mut self) {
+        if let Target::Tag { tag, previous } = self.target {
+            if current_thread_tag() == tag {
+                set_current_thread_tag(previous);
+            }
+        }
+    }
+}
+
+/// Asserts that at most `limit` bytes were allocated within `scope`.
+#[macro_export]
+macro_rules! assert_max_allocated_bytes {
+    ($scope:expr, $limit:expr $(,)?) => {{
+        let stats = $scope.stats();
+        assert!(
+            stats.allocated_bytes <= $limit,
+            "allocated {} bytes, expected at most {}: {:?}",
+            stats.allocated_bytes,
+            $limit,
+            stats
+        );
+    }};
+}
+
+/// Asserts that at most `limit` allocations were made within `scope`.
+#[macro_export]
+macro_rules! assert_max_allocations {
+    ($scope:expr, $limit:expr $(,)?) => {{
+        let stats = $scope.stats();
+        assert!(
+            stats.allocated_count <= $limit,
+            "made {} allocations, expected at most {}: {:?}",
+            stats.allocated_count,
+            $limit,
+            stats
+        );
+    }};
+}
+
+/// Asserts that all memory allocated within `scope` was freed.
+///
+/// This compares totals, so leaking an allocation while freeing a bigger one allocated before
+/// the scope began goes unnoticed.
+#[macro_export]
+macro_rules! assert_no_leaks {
+    ($scope:expr $(,)?) => {{
+        let stats = $scope.stats();
+        assert!(
+            stats.live_bytes() <= 0 && stats.live_count() <= 0,
+            "{} bytes in {} allocations outlived the scope: {:?}",
+            stats.live_bytes(),
+            stats.live_count(),
+            stats
+        );
+    }};
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{current_thread_tag, AllocScope, AllocStats, ProxyAllocator};
+    use std::alloc::{GlobalAlloc, Layout};
+
+    static ALLOC: ProxyAllocator<tikv_jemallocator::Jemalloc> =
+        ProxyAllocator::new(tikv_jemallocator::Jemalloc);
+
+    #[test]
+    fn test_thread_scope() {
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let scope = AllocScope::begin();
+        let ptrs: Vec<_> = (0..3).map(|_| unsafe { ALLOC.alloc(layout) }).collect();
+        assert_max_allocations!(scope, 3);
+        assert_max_allocated_bytes!(scope, 300);
+        assert_eq!(scope.stats().live_bytes(), 300);
+
+        for ptr in ptrs {
+            unsafe { ALLOC.dealloc(ptr, layout) };
+        }
+        assert_no_leaks!(scope);
+        assert_eq!(
+            scope.end(),
+            AllocStats {
+                allocated_bytes: 300,
+                allocated_count: 3,
+                freed_bytes: 300,
+                freed_count: 3
+            }
+        );
+    }
+
+    #[test]
+    fn test_tag_scope() {
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let scope = AllocScope::begin_tag(0x3a1);
+        let ptr = unsafe { ALLOC.alloc(layout) };
+        // Memory allocated with the tag counts against it no matter which thread frees it.
+        let ptr = ptr as usize;
+        std::thread::spawn(move || unsafe { ALLOC.dealloc(ptr as *mut u8, layout) })
+            .join()
+            .unwrap();
+        let stats = scope.end();
+        assert_eq!(stats.allocated_count, 1);
+        assert_eq!(stats.live_count(), 0);
+        assert_eq!(current_thread_tag(), 0);
+    }
+}
@@ -0,0 +1,138 @@
+//! Cache of decisions whether allocations made from a given frame should be attributed to one of
+//! its callers.
+//!
+//! The cache is an open-addressed table of atomics keyed by the exact frame address, so unlike a
+//! bitset keyed by a hash, collisions can't misclassify a frame. When all slots a given address
+//! can occupy are taken, one of them is evicted and its address will be resolved again next time.
+//!
+//! Lookups are counted in shards selected by thread id, like sizes in `histogram`, so that threads
+//! mostly increment their own cache lines.
+use crate::allocator::{get_tid, murmur64};
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const TABLE_SIZE: usize = 1 << 18;
+/// Number of consecutive slots an address can occupy.
+const MAX_PROBE: usize = 8;
+/// Each entry is `addr << 2 | decision`, `0` marks an empty slot.
+static TABLE: [AtomicUsize; TABLE_SIZE] = unsafe {
+    // SAFETY: `usize` and `AtomicUsize` have the same representation.
+    std::mem::transmute::<[usize; TABLE_SIZE], [AtomicUsize; TABLE_SIZE]>([0_usize; TABLE_SIZE])
+};
+const DECISION_BITS: usize = 2;
+const SKIP: usize = 1;
+const KEEP: usize = 2;
+
+const SHARDS: usize = 16;
+
+/// Lookup counters of the threads in a shard, on a cache line of their own.
+#[repr(align(64))]
+struct Lookups {
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+static LOOKUPS: [Lookups; SHARDS] = unsafe {
+    // SAFETY: `Lookups` is two `AtomicUsize`s padded to 64 bytes, zero is a valid value of them.
+    std::mem::transmute::<[[usize; 8]; SHARDS], [Lookups; SHARDS]>([[0_usize; 8]; SHARDS])
+};
+static RESOLVES: AtomicUsize = AtomicUsize::new(0);
+static EVICTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Counters describing how effective the skip cache is.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SkipCacheStats {
+    /// Lookups, which found a decision in the cache.
+    pub hits: usize,
+    /// Lookups, which didn't find a decision in the cache.
+    pub misses: usize,
+    /// Number of times symbols of an address were resolved.
+    pub resolves: usize,
+    /// Entries dropped to make room for new addresses.
+    pub evictions: usize,
+    /// Addresses not queued for asynchronous resolution, because their slot in the queue was
+    /// taken. They are attributed to directly until they are queued again.
+    pub resolver_dropped: usize,
+}
+
+#[must_use]
+pub fn skip_cache_stats() -> SkipCacheStats {
+    SkipCacheStats {
+        hits: LOOKUPS.iter().map(|shard| shard.hits.load(Ordering::Relaxed)).sum(),
+        misses: LOOKUPS.iter().map(|shard| shard.misses.load(Ordering::Relaxed)).sum(),
+        resolves: RESOLVES.load(Ordering::Relaxed),
+        evictions: EVICTIONS.load(Ordering::Relaxed),
+        resolver_dropped: crate::symbol_resolver::DROPPED.load(Ordering::Relaxed),
+    }
+}
+
+fn home(addr: usize) -> usize {
+    murmur64(addr as u64) as usize % TABLE_SIZE
+}
+
+/// Returns the cached decision for `addr`, if any.
+pub(crate) fn get(addr: *mut c_void) -> Option<bool> {
+    let addr = addr as usize;
+    let home = home(addr);
+    let lookups = &LOOKUPS[get_tid() % SHARDS];
+    for i in 0..MAX_PROBE {
+        let entry = TABLE[(home + i) % TABLE_SIZE].load(Ordering::Relaxed);
+        if entry == 0 {
+            break;
+        }
+        if entry >> DECISION_BITS == addr {
+            lookups.hits.fetch_add(1, Ordering::Relaxed);
+            return Some(entry & SKIP != 0);
+        }
+    }
+    lookups.misses.fetch_add(1, Ordering::Relaxed);
+    None
+}
+
+/// Stores the decision for `addr`, which has just been resolved.
+pub(crate) fn insert(addr: *mut c_void, skip: bool) {
+    RESOLVES.fetch_add(1, Ordering::Relaxed);
+    let addr = addr as usize;
+    let entry = (addr << DECISION_BITS) | if skip { SKIP } else { KEEP };
+    let home = home(addr);
+    for i in 0..MAX_PROBE {
+        let slot = &TABLE[(home + i) % TABLE_SIZE];
+        match slot.compare_exchange(0, entry, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return,
+            Err(current) if current >> DECISION_BITS == addr => return,
+            Err(_) => {}
+        }
+    }
+    let victim = EVICTIONS.fetch_add(1, Ordering::Relaxed) % MAX_PROBE;
+    TABLE[(home + victim) % TABLE_SIZE].store(entry, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::skip_cache::{get, home, insert, skip_cache_stats, MAX_PROBE};
+    use std::os::raw::c_void;
+
+    #[test]
+    fn test_exact_lookup() {
+        let addr = 0x1234_5678_usize;
+        // Addresses, which share the same home slot, must not share decisions.
+        let colliding: Vec<usize> =
+            (addr + 1..).filter(|&other| home(other) == home(addr)).take(MAX_PROBE - 1).collect();
+
+        let before = skip_cache_stats();
+        insert(addr as *mut c_void, true);
+        assert_eq!(get(addr as *mut c_void), Some(true));
+        for &other in &colliding {
+            assert_eq!(get(other as *mut c_void), None);
+            insert(other as *mut c_void, false);
+        }
+        assert_eq!(get(addr as *mut c_void), Some(true));
+        for &other in &colliding {
+            assert_eq!(get(other as *mut c_void), Some(false));
+        }
+        // Other tests look up frames concurrently.
+        let after = skip_cache_stats();
+        assert!(after.hits >= before.hits + 2 + colliding.len());
+        assert!(after.misses >= before.misses + colliding.len());
+    }
+}
@@ -0,0 +1,93 @@
+//! Background thread resolving symbols of addresses seen while computing stack traces.
+//!
+//! `backtrace::resolve` may take milliseconds and allocate, so with asynchronous resolution
+//! enabled `alloc` only records new addresses here and the resolver thread classifies them later.
+//! The thread is parked while there is nothing to resolve, e.g. after asynchronous resolution was
+//! disabled.
+use crate::allocator::{classify, IN_TRACE};
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::thread::Thread;
+
+const PENDING_SIZE: usize = 4096;
+/// Addresses waiting to be resolved, `0` marks an empty slot.
+static PENDING: [AtomicUsize; PENDING_SIZE] = unsafe {
+    // SAFETY: `usize` and `AtomicUsize` have the same representation.
+    std::mem::transmute::<[usize; PENDING_SIZE], [AtomicUsize; PENDING_SIZE]>(
+        [0_usize; PENDING_SIZE],
+    )
+};
+/// Addresses not enqueued, because their slot was taken by another address.
+pub(crate) static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+static RESOLVER: OnceLock<Thread> = OnceLock::new();
+/// Set while the resolver thread is about to park or parked, it has to be unparked then.
+static IDLE: AtomicBool = AtomicBool::new(false);
+
+/// Starts the resolver thread, unless it's already running.
+pub(crate) fn start() {
+    RESOLVER.get_or_init(|| {
+        std::thread::Builder::new()
+            .name("symbol-resolver".to_string())
+            .spawn(run)
+            .expect("failed to spawn symbol resolver thread")
+            .thread()
+            .clone()
+    });
+}
+
+/// Schedules `addr` to be resolved.
+///
+/// If the slot is taken by another address, `addr` is dropped and counted in `DROPPED`; it will
+/// be enqueued again the next time it shows up in a stack trace.
+pub(crate) fn enqueue(addr: *mut c_void) {
+    let slot = &PENDING[addr as usize % PENDING_SIZE];
+    match slot.compare_exchange(0, addr as usize, Ordering::SeqCst, Ordering::Relaxed) {
+        Ok(_) => {
+            if IDLE.swap(false, Ordering::SeqCst) {
+                if let Some(resolver) = RESOLVER.get() {
+                    resolver.unpark();
+                }
+            }
+        }
+        Err(other) if other != addr as usize => {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(_) => {}
+    }
+}
+
+/// Number of addresses waiting to be resolved.
+#[cfg(test)]
+pub(crate) fn pending() -> usize {
+    PENDING.iter().filter(|slot| slot.load(Ordering::Relaxed) != 0).count()
+}
+
+fn run() {
+    // Don't compute stack traces of the resolver's own allocations.
+    IN_TRACE.with(|in_trace| in_trace.set(1));
+    loop {
+        if resolve_pending() == 0 {
+            IDLE.store(true, Ordering::SeqCst);
+            // Addresses enqueued before `IDLE` was set don't unpark the thread.
+            if resolve_pending() == 0 {
+                std::thread::park();
+            }
+            IDLE.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Resolves all pending addresses and returns their number.
+fn resolve_pending() -> usize {
+    let mut resolved = 0;
+    for slot in PENDING.iter() {
+        let addr = slot.swap(0, Ordering::SeqCst);
+        if addr != 0 {
+            classify(addr as *mut c_void);
+            resolved += 1;
+        }
+    }
+    resolved
+}
@@ -0,0 +1,154 @@
+//! Stack walking based on frame pointers.
+//!
+//! This is much cheaper than DWARF based unwinding done by `backtrace`, but it only produces
+//! meaningful results if the binary is compiled with `-C force-frame-pointers=yes`. Without frame
+//! pointers the chain is made of arbitrary words, so every return address has to lie in an
+//! executable segment of an object loaded when frame pointers were enabled, see `load_code_ranges`.
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Stop walking after this many frames, in case the chain of frame pointers contains a cycle.
+const MAX_FRAMES: usize = 128;
+
+const MAX_CODE_RANGES: usize = 512;
+/// `(start, end)` of executable segments of loaded objects, `CODE_RANGES_LEN` pairs are valid.
+// SAFETY: `usize` and `AtomicUsize` have the same representation.
+static CODE_RANGES: [AtomicUsize; 2 * MAX_CODE_RANGES] = unsafe {
+    std::mem::transmute::<[usize; 2 * MAX_CODE_RANGES], [AtomicUsize; 2 * MAX_CODE_RANGES]>(
+        [0_usize; 2 * MAX_CODE_RANGES],
+    )
+};
+static CODE_RANGES_LEN: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+thread_local! {
+    /// Highest address of the current thread's stack, `usize::MAX` if it couldn't be determined.
+    static STACK_END: std::cell::Cell<usize> = std::cell::Cell::new(0);
+}
+
+/// Walks the chain of frame pointers of the current thread, calling `f` with each return address
+/// for as long as it returns `true`.
+///
+/// Returns `false` without calling `f` if frame pointers can't be used on this platform, code
+/// ranges weren't loaded or the current frame doesn't have a frame pointer, in which case the
+/// caller should fall back to `backtrace`. The walk stops at the first return address outside of
+/// the code ranges.
+#[inline(always)]
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub(crate) unsafe fn trace(mut f: impl FnMut(*mut c_void) -> bool) -> bool {
+    let mut fp: usize;
+    #[cfg(target_arch = "x86_64")]
+    std::arch::asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack, preserves_flags));
+    #[cfg(target_arch = "aarch64")]
+    std::arch::asm!("mov {}, x29", out(reg) fp, options(nomem, nostack, preserves_flags));
+
+    let stack_end = match stack_end() {
+        Some(stack_end) => stack_end,
+        None => return false,
+    };
+    // The stack grows down, so every frame of our callers lies between our locals and the end.
+    let marker = 0_usize;
+    let stack_start = std::ptr::addr_of!(marker) as usize;
+
+    let mut walked = false;
+    for _ in 0..MAX_FRAMES {
+        if fp < stack_start
+            || fp.saturating_add(2 * std::mem::size_of::<usize>()) > stack_end
+            || fp % std::mem::align_of::<usize>() != 0
+        {
+            break;
+        }
+        // Frame record layout is the same on x86_64 and aarch64: previous frame pointer followed
+        // by the return address.
+        let next_fp = *(fp as *const usize);
+        let return_addr = *((fp + std::mem::size_of::<usize>()) as *const usize);
+        if !is_code(return_addr) {
+            break;
+        }
+        walked = true;
+        if !f(return_addr as *mut c_void) || next_fp <= fp {
+            break;
+        }
+        fp = next_fp;
+    }
+    walked
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+pub(crate) unsafe fn trace(_f: impl FnMut(*mut c_void) -> bool) -> bool {
+    false
+}
+
+/// Records executable segments of all loaded objects. Has to be called before walking with
+/// `trace`, outside of the allocator, as it takes the lock of the dynamic loader.
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub(crate) fn load_code_ranges() {
+    use nix::libc;
+
+    unsafe extern "C" fn visit(
+        info: *mut libc::dl_phdr_info,
+        _size: libc::size_t,
+        len: *mut libc::c_void,
+    ) -> libc::c_int {
+        let len = &mut *len.cast::<usize>();
+        let info = &*info;
+        for i in 0..info.dlpi_phnum as usize {
+            let phdr = &*info.dlpi_phdr.add(i);
+            if phdr.p_type != libc::PT_LOAD || phdr.p_flags & libc::PF_X == 0 {
+                continue;
+            }
+            if *len == MAX_CODE_RANGES {
+                return 1;
+            }
+            let start = info.dlpi_addr as usize + phdr.p_vaddr as usize;
+            CODE_RANGES[2 * *len].store(start, Ordering::Relaxed);
+            CODE_RANGES[2 * *len + 1].store(start + phdr.p_memsz as usize, Ordering::Relaxed);
+            *len += 1;
+        }
+        0
+    }
+
+    let mut len = 0_usize;
+    // Concurrent walks only see ranges up to the previous length until the new one is stored.
+    unsafe { libc::dl_iterate_phdr(Some(visit), std::ptr::addr_of_mut!(len).cast()) };
+    CODE_RANGES_LEN.store(len, Ordering::Release);
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+pub(crate) fn load_code_ranges() {}
+
+/// Whether `addr` lies in an executable segment recorded by `load_code_ranges`.
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn is_code(addr: usize) -> bool {
+    let len = CODE_RANGES_LEN.load(Ordering::Acquire);
+    (0..len).any(|i| {
+        CODE_RANGES[2 * i].load(Ordering::Relaxed) <= addr
+            && addr < CODE_RANGES[2 * i + 1].load(Ordering::Relaxed)
+    })
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn stack_end() -> Option<usize> {
+    use nix::libc;
+
+    STACK_END.with(|stack_end| {
+        if stack_end.get() == 0 {
+            let mut value = usize::MAX;
+            // SAFETY: `attr` is initialized by `pthread_getattr_np` before being read and is
+            // destroyed afterwards.
+            unsafe {
+                let mut attr = std::mem::MaybeUninit::<libc::pthread_attr_t>::uninit();
+                if libc::pthread_getattr_np(libc::pthread_self(), attr.as_mut_ptr()) == 0 {
+                    let mut addr = std::ptr::null_mut::<libc::c_void>();
+                    let mut size = 0;
+                    if libc::pthread_attr_getstack(attr.as_ptr(), &mut addr, &mut size) == 0 {
+                        value = addr as usize + size;
+                    }
+                    libc::pthread_attr_destroy(attr.as_mut_ptr());
+                }
+            }
+            stack_end.set(value);
+        }
+        Some(stack_end.get()).filter(|&v| v != usize::MAX)
+    })
+}
@@ -1,115 +1,615 @@
-use crate::symbols::get_symbols;
-use crate::utils::{compute_present_pages, get_page_size, read_smaps, Counter, Smap, MIB};
+use crate::core_dump::CoreDump;
+use crate::freeze::Freeze;
+use crate::heap_dump::HeapDump;
+use crate::mmap_sites;
+use crate::symbols::{find_symbol, get_symbols, Symbol};
+use crate::utils::{
+    compute_pages, get_page_size, jemalloc_size_class, page_usage, read_build_id, read_page_map,
+    read_smaps, Counter, PageMapEntry, SizeHistogram, Smap, MIB,
+};
 use anyhow::Context;
+use inferno::flamegraph;
 use itertools::Itertools;
-use near_rust_allocator_proxy::AllocHeader;
+use near_rust_allocator_proxy::pprof::{Mapping, ProfileBuilder};
+use near_rust_allocator_proxy::{AllocHeader, LiveMapping};
 use nix::sys::uio::{IoVec, RemoteIoVec};
 use nix::unistd::Pid;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::c_void;
 use std::fs;
 use std::fs::File;
+use std::io::BufWriter;
 use std::ops::Not;
-use std::path::PathBuf;
-use std::time::Instant;
-use tracing::{debug, error, info};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
 
 #[derive(clap_derive::Parser, Debug)]
 pub(crate) struct AnalyzeCmd {
+    #[clap(long, required_unless_present_any(&["core", "dump"]))]
+    pid: Option<i32>,
+    /// Analyze an ELF core dump instead of a running process. Requires `--exe`.
+    #[clap(long, conflicts_with_all(&["pid", "dump", "watch"]), requires("exe"))]
+    core: Option<PathBuf>,
+    /// Analyze a heap dump written by `dump` instead of a running process.
+    #[clap(long, conflicts_with_all(&["pid", "watch"]))]
+    dump: Option<PathBuf>,
+    /// Executable of the process, which the core or heap dump was taken from. Defaults to the
+    /// path stored in the heap dump.
     #[clap(long)]
-    pid: i32,
+    exe: Option<PathBuf>,
     #[clap(long)]
     print_raw_symbols: bool,
-    #[clap(long, conflicts_with("print_raw_symbols"))]
+    #[clap(long, conflicts_with("print-raw-symbols"))]
     print_ptr: bool,
+    /// Write memory usage per stack as a gzip compressed pprof profile.
+    #[clap(long)]
+    output_pprof: Option<PathBuf>,
+    /// Write memory usage per stack in the collapsed format used by flamegraph tools.
+    #[clap(long)]
+    collapsed: Option<PathBuf>,
+    /// Write memory usage per stack as a flamegraph SVG.
+    #[clap(long)]
+    flamegraph: Option<PathBuf>,
+    /// Report the distribution of live allocation sizes, in total and for each function with at
+    /// least 1 MiB allocated. Only sampled allocations are attributed to functions.
+    #[clap(long)]
+    size_histogram: bool,
+    /// Also look for allocation headers on swapped out pages. Reading them makes the kernel swap
+    /// them back in.
+    #[clap(long)]
+    read_swapped: bool,
+    /// Repeat the analysis every given number of seconds.
+    #[clap(long)]
+    watch: Option<u64>,
+    /// Only re-read pages, which were written to since the previous analysis, according to their
+    /// soft-dirty bits. Requires `--watch`, `--pid` and `--consistent`, so that no writes happen
+    /// between reading the soft-dirty bits and clearing them.
+    #[clap(long, requires_all(&["watch", "pid", "consistent"]))]
+    incremental: bool,
+    /// Stop all threads of the process while its memory is read, so that allocations don't change
+    /// during the scan. The process is resumed once the scan is done, fails or times out, or the
+    /// analyzer exits.
+    #[clap(long, conflicts_with_all(&["core", "dump"]))]
+    consistent: bool,
+    /// Maximum number of seconds the process is kept stopped with `--consistent`, the scan fails
+    /// after that.
+    #[clap(long, default_value = "10")]
+    freeze_timeout: u64,
+}
+
+/// Allocation header found while scanning a page.
+#[derive(Debug, Clone)]
+struct FoundAllocation {
+    address: usize,
+    size: usize,
+    /// Frames of the stack trace, innermost first, empty if the allocation wasn't sampled.
+    stack: Vec<*mut c_void>,
+    kind: HeaderKind,
+}
+
+/// Proxy, which wrote a header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HeaderKind {
+    /// `AllocHeader` of `near-rust-allocator-proxy`, also written by `near-c-allocator-proxy`.
+    Rust,
+    /// `CAllocHeader` of `near-dump-analyzer/near-c-allocator-proxy.c`.
+    C,
+}
+
+/// Magic of headers of allocations made by `near-dump-analyzer/near-c-allocator-proxy.c`. It has
+/// no checksum, freeing adds 0x100 to it.
+const C_MAGIC: usize = 0x12_3456_7899_1301;
+
+/// Header written by `near-dump-analyzer/near-c-allocator-proxy.c` in front of C and C++
+/// allocations, it has the same size as `AllocHeader`.
+#[derive(Debug)]
+#[repr(C)]
+struct CAllocHeader {
+    magic: usize,
+    size: usize,
+    tid: usize,
+    /// Innermost frame in the executable, 0 or 1 if the allocation wasn't sampled.
+    func: *mut c_void,
+}
+
+impl CAllocHeader {
+    /// The frame the allocation is attributed to, empty if it wasn't sampled.
+    fn frames(&self) -> &[*mut c_void] {
+        &std::slice::from_ref(&self.func)[..usize::from(self.func as usize > 1)]
+    }
+}
+
+/// Memory being analyzed.
+pub(crate) enum Target {
+    Process { pid: i32, pagemap: File },
+    Core(CoreDump),
+    Dump(HeapDump),
+}
+
+impl Target {
+    /// Same as [`compute_pages`], with pagemap entries taken from the dump for dumps.
+    pub(crate) fn compute_pages(
+        &mut self,
+        smaps: &[Smap],
+        page_size: usize,
+        mapped: bool,
+        filter: impl Fn(PageMapEntry) -> bool,
+    ) -> anyhow::Result<Vec<(Smap, Vec<usize>)>> {
+        compute_pages(smaps, mapped, filter, |smap| match self {
+            Target::Process { pagemap, .. } => read_page_map(smap, pagemap, page_size),
+            Target::Core(core) => Ok(core.page_map(smap, page_size)),
+            Target::Dump(dump) => Ok(dump.page_map(smap)),
+        })
+    }
+
+    /// Fills `buffer` with memory starting at `address`.
+    pub(crate) fn read(&self, address: usize, buffer: &mut [u8]) -> anyhow::Result<()> {
+        match self {
+            Target::Process { pid, .. } => {
+                let input = [IoVec::from_mut_slice(buffer)];
+                let output = [RemoteIoVec { base: address, len: input[0].as_slice().len() }];
+                nix::sys::uio::process_vm_readv(Pid::from_raw(*pid), &input, &output)?;
+                Ok(())
+            }
+            Target::Core(core) => core.read(address, buffer),
+            Target::Dump(dump) => dump.read(address, buffer),
+        }
+    }
+}
+
+/// Number of words matching the header magic, which were rejected for each reason.
+#[derive(Debug, Default)]
+struct Rejected {
+    /// The checksum in the magic doesn't match the other fields.
+    checksum: usize,
+    /// The allocation doesn't fit into its mapping.
+    size: usize,
+    /// The thread id can't be a valid one.
+    tid: usize,
+    /// The header is inside of the previous allocation, e.g. a copy of a header.
+    overlap: usize,
+}
+
+/// Maximum number of thread ids on 64 bit linux.
+const PID_MAX_LIMIT: usize = 1 << 22;
+
+/// Checks, which headers have to pass besides the magic.
+#[derive(Debug, Default)]
+pub(crate) struct HeaderCheck {
+    /// `(from, to)` of executable mappings, stack frames have to be in one of them.
+    executable: Vec<(usize, usize)>,
+    /// End of the run of adjacent writable mappings by the end of each mapping in it. The kernel
+    /// splits mappings e.g. when parts of them are madvised, allocations can span the parts.
+    run_end: HashMap<usize, usize>,
+    rejected: Rejected,
+    /// Number of accepted headers written by `near-rust-allocator-proxy` before 1.0.
+    legacy: usize,
+    /// Number of accepted headers with stack frames outside of executable mappings, e.g. in
+    /// libraries unloaded since. Their allocations are counted without a stack trace.
+    unattributed: usize,
+}
+
+impl HeaderCheck {
+    pub(crate) fn new(smaps: &[Smap]) -> Self {
+        let writable = |smap: &Smap| smap.perms.starts_with("rw");
+        let mut run_end = HashMap::new();
+        let mut next: Option<(&Smap, usize)> = None;
+        for smap in smaps.iter().rev() {
+            let end = match next {
+                Some((next, end)) if next.from == smap.to && writable(next) && writable(smap) => {
+                    end
+                }
+                _ => smap.to,
+            };
+            run_end.insert(smap.to, end);
+            next = Some((smap, end));
+        }
+        Self {
+            executable: (smaps.iter())
+                .filter(|smap| smap.perms.as_bytes().get(2) == Some(&b'x'))
+                .map(|smap| (smap.from, smap.to))
+                .collect(),
+            run_end,
+            rejected: Rejected::default(),
+            legacy: 0,
+            unattributed: 0,
+        }
+    }
+
+    /// Whether `ah` found at `address` in a mapping ending at `region_end` is the header of a
+    /// live allocation. Overlaps with other allocations are checked by the caller.
+    fn accept(&mut self, ah: &AllocHeader, address: usize, region_end: usize) -> bool {
+        if ah.is_legacy_allocated() {
+            // No checksum, checked like headers of the C proxy.
+            let accepted = self.accept_unchecksummed(ah.size(), ah.tid(), address, region_end);
+            self.legacy += usize::from(accepted);
+            return accepted;
+        }
+        if !ah.is_allocated() {
+            if ah.has_magic() && !ah.is_freed() && !ah.is_legacy_freed() {
+                self.rejected.checksum += 1;
+            }
+            return false;
+        }
+        let rejected = match self.implausible_field(ah, address, region_end) {
+            Some("size") => &mut self.rejected.size,
+            Some(_) => &mut self.rejected.tid,
+            None => return true,
+        };
+        *rejected += 1;
+        false
+    }
+
+    /// Same as `accept` for a header of the C proxy, whose magic has been checked by the caller.
+    fn accept_c(&mut self, header: &CAllocHeader, address: usize, region_end: usize) -> bool {
+        self.accept_unchecksummed(header.size, header.tid, address, region_end)
+    }
+
+    /// Whether a header without a checksum with the given fields is plausible. Without a checksum
+    /// a size reaching into the next mapping is more likely random data than an allocation.
+    fn accept_unchecksummed(
+        &mut self,
+        size: usize,
+        tid: usize,
+        address: usize,
+        region_end: usize,
+    ) -> bool {
+        let rejected = match self.implausible(size, tid, address, region_end) {
+            Some("size") => &mut self.rejected.size,
+            Some(_) => &mut self.rejected.tid,
+            None => return true,
+        };
+        *rejected += 1;
+        false
+    }
+
+    /// Name of the first field of `ah` found at `address` in a mapping ending at `region_end`,
+    /// which can't be the one of an allocation: `size` or `tid`. The allocation can reach into
+    /// adjacent writable mappings.
+    pub(crate) fn implausible_field(
+        &self,
+        ah: &AllocHeader,
+        address: usize,
+        region_end: usize,
+    ) -> Option<&'static str> {
+        let region_end = self.run_end.get(&region_end).copied().unwrap_or(region_end);
+        self.implausible(ah.size(), ah.tid(), address, region_end)
+    }
+
+    fn implausible(
+        &self,
+        size: usize,
+        tid: usize,
+        address: usize,
+        region_end: usize,
+    ) -> Option<&'static str> {
+        let end = (address + std::mem::size_of::<AllocHeader>()).checked_add(size);
+        if size >= u32::MAX as usize || end.map_or(true, |end| end > region_end) {
+            Some("size")
+        } else if tid == 0 || tid > PID_MAX_LIMIT {
+            Some("tid")
+        } else {
+            None
+        }
+    }
+
+    /// Stack trace of an accepted header with `frames`, empty if one of them isn't in an
+    /// executable mapping.
+    fn stack(&mut self, frames: &[*mut c_void]) -> Vec<*mut c_void> {
+        let in_executable =
+            |ptr: usize| self.executable.iter().any(|&(from, to)| from <= ptr && ptr < to);
+        if frames.iter().all(|&ptr| in_executable(ptr as usize)) {
+            frames.to_vec()
+        } else {
+            self.unattributed += 1;
+            Vec::new()
+        }
+    }
+}
+
+/// State kept between analyses with `--watch`.
+#[derive(Debug, Default)]
+struct WatchState {
+    symbols: Option<(PathBuf, Vec<Symbol>)>,
+    /// Allocations found on each page during the previous scan, if scanning incrementally.
+    pages: Option<HashMap<usize, Vec<FoundAllocation>>>,
+    /// The kernel doesn't track soft-dirty bits, so every scan is a full one.
+    no_soft_dirty: bool,
 }
 
 impl AnalyzeCmd {
     pub(crate) fn handle(&self) -> anyhow::Result<()> {
-        info!(?self.pid);
-        let smaps = read_smaps(self.pid).with_context(|| "read_smaps failed")?;
+        let mut state = WatchState::default();
+        loop {
+            self.analyze(&mut state)?;
+            match self.watch {
+                Some(interval) => std::thread::sleep(Duration::from_secs(interval)),
+                None => return Ok(()),
+            }
+        }
+    }
 
+    fn analyze(&self, state: &mut WatchState) -> anyhow::Result<()> {
         let start = Instant::now();
-        let page_map_file = PathBuf::from("/proc").join(self.pid.to_string()).join("pagemap");
-        let mut file = File::open(page_map_file.clone())
-            .with_context(|| format!("page_map_file not found file={:?}", page_map_file))?;
+        // Stopped before reading smaps, so that mappings match the memory which is read.
+        let freeze = match self.pid {
+            Some(pid) if self.consistent => {
+                Some(Freeze::new(pid, Duration::from_secs(self.freeze_timeout))?)
+            }
+            _ => None,
+        };
+        let (smaps, mut target, exe_path, mapped_exe_path) = match (&self.core, self.pid) {
+            (Some(core), _) => {
+                info!(?core);
+                let core = CoreDump::open(core)?;
+                let smaps = core.smaps();
+                let exe_path = self.exe.clone().with_context(|| "--exe is required with --core")?;
+                info!(?exe_path);
+                // The executable may have been at a different path on the machine the core dump
+                // was taken on.
+                let mapped_exe_path = smaps
+                    .iter()
+                    .filter_map(|smap| smap.mapped_file.as_deref())
+                    .find(|file| Path::new(file).file_name() == exe_path.file_name())
+                    .map_or_else(|| exe_path.clone(), PathBuf::from);
+                (smaps, Target::Core(core), exe_path, mapped_exe_path)
+            }
+            (None, _) if self.dump.is_some() => {
+                let path = self.dump.as_ref().unwrap();
+                info!(?path);
+                let dump = HeapDump::open(path)?;
+                let exe_path = self.exe.clone().unwrap_or_else(|| dump.exe_path.clone());
+                info!(?exe_path);
+                let build_id = read_build_id(&exe_path).unwrap_or_default();
+                if build_id != dump.build_id {
+                    warn!(?build_id, ?dump.build_id, "Executable differs from the dumped one.");
+                }
+                let mapped_exe_path = dump.exe_path.clone();
+                (dump.smaps(), Target::Dump(dump), exe_path, mapped_exe_path)
+            }
+            (None, Some(pid)) => {
+                info!(?pid);
+                let smaps = read_smaps(pid).with_context(|| "read_smaps failed")?;
 
-        let page_size = get_page_size()?;
-        info!(?page_size);
+                let page_map_file = PathBuf::from("/proc").join(pid.to_string()).join("pagemap");
+                let pagemap = File::open(page_map_file.clone())
+                    .with_context(|| format!("page_map_file not found file={:?}", page_map_file))?;
 
-        let proc_exe_path = PathBuf::from("/proc").join(self.pid.to_string()).join("exe");
-        info!(?proc_exe_path);
-        let exe_path = fs::read_link(proc_exe_path).with_context(|| "unable to read exe path")?;
-        info!(?exe_path);
+                let proc_exe_path = PathBuf::from("/proc").join(pid.to_string()).join("exe");
+                info!(?proc_exe_path);
+                let exe_path =
+                    fs::read_link(proc_exe_path).with_context(|| "unable to read exe path")?;
+                info!(?exe_path);
+                (smaps, Target::Process { pid, pagemap }, exe_path.clone(), exe_path)
+            }
+            (None, None) => anyhow::bail!("either --pid, --core or --dump is required"),
+        };
+
+        let page_size = match &target {
+            Target::Dump(dump) => dump.page_size,
+            _ => get_page_size()?,
+        };
+        info!(?page_size);
 
-        let mmaped_exec = Self::get_mmaped_exe_regions(&smaps, exe_path.clone());
+        let mmaped_exec = Self::get_mmaped_exe_regions(&smaps, mapped_exe_path);
         info!(mapped_exec_len = ?mmaped_exec.len());
         // compute memory used in not mmaped files
 
-        let mut ptr_2_memory: HashMap<*mut c_void, Counter> = HashMap::new();
+        let mut stack_2_memory: HashMap<Vec<*mut c_void>, Counter> = HashMap::new();
+        // Sizes of sampled allocations per frame, the total histogram has all of them.
+        let mut ptr_2_sizes: HashMap<*mut c_void, SizeHistogram> = HashMap::new();
+        let mut all_sizes = SizeHistogram::default();
+        // All allocations, including ones without a stack trace.
+        let mut all_allocations = Counter::default();
+        // All allocations by the proxy, which wrote their header.
+        let (mut rust_allocations, mut c_allocations) = (Counter::default(), Counter::default());
+        let mut extents: Vec<(usize, usize)> = Vec::new();
+        let mut size_class_rounding = 0;
 
         info!("Reading pages.");
         let mut buffer = vec![0u8; page_size + std::mem::size_of::<AllocHeader>()];
 
         let not_mmaped_pages: Vec<_> =
-            compute_present_pages(&smaps, &mut file, page_size, false)?.to_vec();
+            target.compute_pages(&smaps, page_size, false, PageMapEntry::present)?;
         let total_present_pages: usize = not_mmaped_pages.iter().map(|x| (x.1.len())).sum();
+        let total_swapped_pages: usize = target
+            .compute_pages(&smaps, page_size, false, PageMapEntry::swapped)?
+            .iter()
+            .map(|x| x.1.len())
+            .sum();
+        info!(swapped_mb = total_swapped_pages * page_size / MIB, "Swapped out anonymous memory.");
+        let swapped_and_present_pages = if self.read_swapped {
+            Some(target.compute_pages(&smaps, page_size, false, |entry| {
+                entry.present() || entry.swapped()
+            })?)
+        } else {
+            if total_swapped_pages > 0 {
+                warn!("Allocations on swapped out pages are missing, use --read-swapped to read them.");
+            }
+            None
+        };
+        let previous_pages = state.pages.take();
+        let dirty_pages: Option<HashSet<usize>> = if self.incremental && !state.no_soft_dirty {
+            let dirty: HashSet<usize> = target
+                .compute_pages(&smaps, page_size, false, PageMapEntry::soft_dirty)?
+                .into_iter()
+                .flat_map(|(_, pages)| pages)
+                .collect();
+            if previous_pages.is_none() && dirty.is_empty() {
+                // Fresh pages are always soft-dirty, unless the kernel doesn't track it.
+                warn!("No soft-dirty pages found, falling back to full scans.");
+                state.no_soft_dirty = true;
+                None
+            } else {
+                // Pages written to from now on will be re-read next time. The process is stopped
+                // since pagemap was read, so no writes are missed.
+                let pid = self.pid.expect("clap requires --pid with --incremental");
+                let clear_refs = PathBuf::from("/proc").join(pid.to_string()).join("clear_refs");
+                fs::write(&clear_refs, "4")
+                    .with_context(|| format!("failed to clear soft-dirty bits {:?}", clear_refs))?;
+                Some(dirty)
+            }
+        } else {
+            None
+        };
+        let mut pages: HashMap<usize, Vec<FoundAllocation>> = HashMap::new();
+        let mut reused_pages = 0;
+        let mut check = HeaderCheck::new(&smaps);
+
         info!("Read pages.");
-        for (smap, addresses) in not_mmaped_pages.iter() {
+        for (smap, addresses) in swapped_and_present_pages.as_ref().unwrap_or(&not_mmaped_pages) {
             debug!(?smap, len = addresses.len());
             assert_eq!((smap.to - smap.from) % page_size, 0, "pages not multiple of {}", page_size);
+            if smap.is_stack {
+                // Headers are built on the stack before being written to the heap.
+                continue;
+            }
+            // End of the last accepted allocation, headers before it are inside of it.
+            let mut accepted_end = 0;
 
             for ad in addresses {
-                let input = [IoVec::from_mut_slice(buffer.as_mut_slice())];
-                let output = [RemoteIoVec { base: *ad, len: page_size }];
-
-                nix::sys::uio::process_vm_readv(Pid::from_raw(self.pid), &input, &output)?;
-                // TODO: Allocation headers, which are split between 2 consecutive pages are not counter correctly.
-                for val in (0..page_size / 8).map(|v| v * 8) {
-                    let ah = unsafe {
-                        &mut *(buffer.as_mut_slice()[val..].as_ptr() as *mut AllocHeader)
-                    };
-                    if ah.is_allocated() {
-                        let ptr = ah.stack()[0];
-                        if ptr != usize::MAX as *mut c_void
-                            && ptr.is_null().not()
-                            && ah.size() < u32::MAX as usize
-                        {
-                            *ptr_2_memory.entry(ptr).or_default() += Counter::with_size(ah.size());
-                        }
+                if let Some(freeze) = &freeze {
+                    freeze.check()?;
+                }
+                let cached = match (&previous_pages, &dirty_pages) {
+                    (Some(previous), Some(dirty)) if !dirty.contains(ad) => previous.get(ad),
+                    _ => None,
+                };
+                let allocations = match cached {
+                    Some(allocations) => {
+                        reused_pages += 1;
+                        allocations.clone()
+                    }
+                    None => {
+                        Self::scan_page(&target, &mut buffer, *ad, page_size, smap.to, &mut check)?
                     }
+                };
+                for allocation in &allocations {
+                    let len = std::mem::size_of::<AllocHeader>() + allocation.size;
+                    if allocation.address < accepted_end {
+                        check.rejected.overlap += 1;
+                        continue;
+                    }
+                    accepted_end = allocation.address + len;
+                    all_allocations += Counter::with_size(allocation.size);
+                    match allocation.kind {
+                        HeaderKind::Rust => rust_allocations += Counter::with_size(allocation.size),
+                        HeaderKind::C => c_allocations += Counter::with_size(allocation.size),
+                    }
+                    extents.push((allocation.address, len));
+                    size_class_rounding += jemalloc_size_class(len) - len;
+                    if self.size_histogram {
+                        all_sizes.add(allocation.size);
+                    }
+
+                    let stack = allocation.stack.as_slice();
+                    if stack.is_empty() {
+                        continue;
+                    }
+                    if let Some(counter) = stack_2_memory.get_mut(stack) {
+                        *counter += Counter::with_size(allocation.size);
+                    } else {
+                        stack_2_memory.insert(stack.to_vec(), Counter::with_size(allocation.size));
+                    }
+                    if self.size_histogram {
+                        ptr_2_sizes.entry(stack[0]).or_default().add(allocation.size);
+                    }
+                }
+                if dirty_pages.is_some() {
+                    pages.insert(*ad, allocations);
                 }
             }
         }
+        // Read while the process is stopped, so that it matches the pages found.
+        let (live_mappings, dropped_mappings) = mmap_sites::read_mmap_logs(&target, &smaps);
+        drop(freeze);
+        info!(rejected = ?check.rejected, "Rejected words matching the header magic.");
+        if check.unattributed != 0 {
+            warn!(
+                unattributed = check.unattributed,
+                "Allocations with stack frames outside of executable mappings are counted as \
+                 unsampled, e.g. ones made by unloaded libraries."
+            );
+        }
+        if check.legacy != 0 {
+            warn!(
+                legacy = check.legacy,
+                "Found headers of near-rust-allocator-proxy before 1.0, they have no checksum."
+            );
+        }
+        info!(
+            rust_count = rust_allocations.cnt,
+            rust_mb = rust_allocations.size / MIB,
+            c_count = c_allocations.cnt,
+            c_mb = c_allocations.size / MIB,
+            "Allocations by header, C headers are written by near-c-allocator-proxy.c."
+        );
+        if dirty_pages.is_some() {
+            info!(reused_pages, read_pages = pages.len() - reused_pages, "Scanned incrementally.");
+            state.pages = Some(pages);
+        }
         info!("Getting exe path");
 
-        let str_exe_path = exe_path.to_str().unwrap();
-        info!(?str_exe_path, "Getting symbols.");
-        let symbols = get_symbols(str_exe_path)?;
-        info!(symbols = symbols.len());
+        let symbols = match &state.symbols {
+            Some((path, symbols)) if *path == exe_path => symbols,
+            _ => {
+                let str_exe_path = exe_path.to_str().unwrap();
+                info!(?str_exe_path, "Getting symbols.");
+                let symbols = get_symbols(str_exe_path)?;
+                info!(symbols = symbols.len());
+                &state.symbols.insert((exe_path.clone(), symbols)).1
+            }
+        };
+
+        if let Some(output_pprof) = &self.output_pprof {
+            Self::write_pprof(output_pprof, &smaps, &mmaped_exec, symbols, &stack_2_memory)
+                .with_context(|| format!("failed to write pprof profile to {:?}", output_pprof))?;
+            info!(?output_pprof, "Wrote pprof profile.");
+        }
+
+        if self.collapsed.is_some() || self.flamegraph.is_some() {
+            let collapsed = self.collapsed_stacks(&mmaped_exec, symbols, &stack_2_memory);
+            if let Some(path) = &self.collapsed {
+                fs::write(path, collapsed.iter().map(|line| format!("{}\n", line)).join(""))
+                    .with_context(|| format!("failed to write collapsed stacks to {:?}", path))?;
+                info!(?path, "Wrote collapsed stacks.");
+            }
+            if let Some(path) = &self.flamegraph {
+                let mut options = flamegraph::Options::default();
+                options.title = "Memory usage".to_string();
+                options.count_name = "bytes".to_string();
+                let file = File::create(path)
+                    .with_context(|| format!("failed to create flamegraph {:?}", path))?;
+                flamegraph::from_lines(
+                    &mut options,
+                    collapsed.iter().map(String::as_str),
+                    BufWriter::new(file),
+                )
+                .map_err(|err| anyhow::anyhow!("failed to write flamegraph: {:?}", err))?;
+                info!(?path, "Wrote flamegraph.");
+            }
+        }
+
+        // Attribute memory to the innermost frame.
+        let mut ptr_2_memory: HashMap<*mut c_void, Counter> = HashMap::new();
+        for (stack, counter) in stack_2_memory.iter() {
+            *ptr_2_memory.entry(stack[0]).or_default() += *counter;
+        }
 
         let mut func_2_mem: HashMap<String, Counter> = HashMap::new();
         let present_allocated_with_proxy = ptr_2_memory.iter().map(|x| x.1.size).sum();
         for (ptr, val) in ptr_2_memory.iter() {
-            let symbol_mappings = (mmaped_exec.iter())
-                .filter(|x| ((x.from as *mut c_void) <= (*ptr)) && ((*ptr as usize) < x.to))
-                .filter_map(|smap| {
-                    let file_offset = (*ptr as usize) - smap.from + smap.offset;
-
-                    if let Some(last_sym) =
-                        symbols.iter().filter(|s| s.offset <= file_offset).last()
-                    {
-                        let key = if self.print_ptr {
-                            format!("{:?}", ptr)
-                        } else if self.print_raw_symbols {
-                            last_sym.raw_symbol.clone()
-                        } else {
-                            last_sym.symbol.clone()
-                        };
-                        Some((key, val))
+            let symbol_mappings = Self::resolve_ptr(*ptr, &mmaped_exec, symbols)
+                .map(|sym| {
+                    let key = if self.print_ptr {
+                        format!("{:?}", ptr)
+                    } else if self.print_raw_symbols {
+                        sym.raw_symbol.clone()
                     } else {
-                        None
-                    }
+                        sym.symbol.clone()
+                    };
+                    (key, val)
                 })
                 .collect_vec();
             if symbol_mappings.is_empty() {
@@ -130,8 +630,13 @@ impl AnalyzeCmd {
             info!(?func, count = counter.cnt, size_mb = counter.size / MIB);
         }
 
-        let mapped_file_pages: usize = compute_present_pages(&smaps, &mut file, page_size, true)
-            .with_context(|| "compute_present_pages")?
+        if self.size_histogram {
+            self.print_size_histograms(&mmaped_exec, symbols, &all_sizes, &ptr_2_sizes);
+        }
+
+        let mapped_file_pages: usize = target
+            .compute_pages(&smaps, page_size, true, PageMapEntry::present)
+            .with_context(|| "compute_pages")?
             .iter()
             .map(|x| x.1.len())
             .sum();
@@ -144,6 +649,322 @@ impl AnalyzeCmd {
         info!(took = ?start.elapsed(), total_size_mb);
 
         info!(took = ?start.elapsed(), resident_but_not_used_mb, allocated_with_proxy_mb = present_allocated_with_proxy / MIB, mapped_files_mb);
+
+        let breakdown = Self::unused_breakdown(
+            &not_mmaped_pages,
+            extents,
+            page_size,
+            all_allocations,
+            present_allocated_with_proxy,
+            size_class_rounding,
+        );
+        let resident_but_not_used =
+            (total_present_pages * page_size).saturating_sub(present_allocated_with_proxy);
+        let unknown =
+            breakdown.iter().fold(resident_but_not_used, |v, (_, b)| v.saturating_sub(*b));
+        for (name, bytes) in breakdown.into_iter().chain([("unknown", unknown)]) {
+            info!(size_mb = bytes / MIB, "resident_but_not_used: {}", name);
+        }
+        if !live_mappings.is_empty() || dropped_mappings != 0 {
+            self.print_mmap_sites(
+                &live_mappings,
+                dropped_mappings,
+                &not_mmaped_pages,
+                page_size,
+                &mmaped_exec,
+                symbols,
+            );
+        }
+        Ok(())
+    }
+
+    /// Logs resident anonymous memory in mappings, which are in the table of an mmap log, per
+    /// function, which made them, and how much of it is in other mappings.
+    fn print_mmap_sites(
+        &self,
+        found: &[LiveMapping],
+        dropped: usize,
+        not_mmaped_pages: &[(Smap, Vec<usize>)],
+        page_size: usize,
+        mmaped_exec: &[Smap],
+        symbols: &[Symbol],
+    ) {
+        let mappings = mmap_sites::live_mappings(found);
+        let pages = not_mmaped_pages.iter().flat_map(|(_, pages)| pages.iter().copied());
+        let total: usize = not_mmaped_pages.iter().map(|(_, pages)| pages.len() * page_size).sum();
+        let mut func_2_mem: HashMap<String, Counter> = HashMap::new();
+        for (site, counter) in mmap_sites::resident_by_site(&mappings, pages, page_size) {
+            let func = if site.is_null() {
+                "unknown".to_string()
+            } else {
+                self.frame_name(site, mmaped_exec, symbols)
+            };
+            *func_2_mem.entry(func).or_default() += counter;
+        }
+        let resident: usize = func_2_mem.values().map(|counter| counter.size).sum();
+        info!(
+            mappings = mappings.len(),
+            resident_mb = resident / MIB,
+            unattributed_mb = total.saturating_sub(resident) / MIB,
+            "Resident anonymous memory in mappings from the mmap log."
+        );
+        if dropped != 0 {
+            warn!(
+                dropped,
+                "Mappings, which didn't fit into the table of the mmap log, are unattributed."
+            );
+        }
+        let mut func_2_mem: Vec<_> = func_2_mem.into_iter().collect();
+        func_2_mem.sort_by_key(|(_, counter)| counter.size);
+        for (func, counter) in func_2_mem.iter().filter(|(_, counter)| counter.size >= MIB) {
+            info!(?func, mappings = counter.cnt, size_mb = counter.size / MIB, "mmap site");
+        }
+    }
+
+    /// Reads the page at `address` and returns allocations with headers of either proxy on it,
+    /// which pass `check`.
+    fn scan_page(
+        target: &Target,
+        buffer: &mut [u8],
+        address: usize,
+        page_size: usize,
+        region_end: usize,
+        check: &mut HeaderCheck,
+    ) -> anyhow::Result<Vec<FoundAllocation>> {
+        target.read(address, &mut buffer[..page_size])?;
+        let mut res = Vec::new();
+        // TODO: Allocation headers, which are split between 2 consecutive pages are not counter correctly.
+        for val in (0..page_size / 8).map(|v| v * 8) {
+            res.extend(Self::find_header(&buffer[val..], address + val, region_end, check));
+        }
+        Ok(res)
+    }
+
+    /// Allocation with its header at the start of `bytes`, which were read from `address`, if it
+    /// passes `check`. `bytes` has to be at least as long as a header.
+    fn find_header(
+        bytes: &[u8],
+        address: usize,
+        region_end: usize,
+        check: &mut HeaderCheck,
+    ) -> Option<FoundAllocation> {
+        assert!(bytes.len() >= std::mem::size_of::<AllocHeader>());
+        let ah = unsafe { &*(bytes.as_ptr() as *const AllocHeader) };
+        if check.accept(ah, address, region_end) {
+            let stack = check.stack(Self::frames(ah));
+            return Some(FoundAllocation {
+                address,
+                size: ah.size(),
+                stack,
+                kind: HeaderKind::Rust,
+            });
+        }
+        let header = unsafe { &*(bytes.as_ptr() as *const CAllocHeader) };
+        if header.magic == C_MAGIC && check.accept_c(header, address, region_end) {
+            let stack = check.stack(header.frames());
+            return Some(FoundAllocation {
+                address,
+                size: header.size,
+                stack,
+                kind: HeaderKind::C,
+            });
+        }
+        None
+    }
+
+    /// Splits resident anonymous memory, which isn't used by allocations with a stack trace, into
+    /// categories. Returns `(category, bytes)` pairs, memory not in any category is unknown.
+    ///
+    /// `extents` are `(start, len)` of all allocations including headers, in any order.
+    fn unused_breakdown(
+        not_mmaped_pages: &[(Smap, Vec<usize>)],
+        mut extents: Vec<(usize, usize)>,
+        page_size: usize,
+        all_allocations: Counter,
+        sampled_bytes: usize,
+        size_class_rounding: usize,
+    ) -> Vec<(&'static str, usize)> {
+        extents.sort_unstable();
+        // Drop headers found inside other allocations, they are stale copies.
+        let mut end = 0;
+        extents.retain(|&(start, len)| {
+            let keep = start >= end;
+            if keep {
+                end = start + len;
+            }
+            keep
+        });
+
+        let (mut thread_stacks, mut free_pages, mut partially_used) = (0, 0, 0);
+        for (smap, pages) in not_mmaped_pages {
+            if smap.is_stack {
+                thread_stacks += pages.len() * page_size;
+                continue;
+            }
+            for used in page_usage(pages, &extents, page_size) {
+                if used == 0 {
+                    free_pages += page_size;
+                } else if used < page_size {
+                    partially_used += page_size - used;
+                }
+            }
+        }
+        vec![
+            ("unsampled_allocations", all_allocations.size.saturating_sub(sampled_bytes)),
+            ("header_overhead", all_allocations.cnt * std::mem::size_of::<AllocHeader>()),
+            ("size_class_rounding", size_class_rounding.min(partially_used)),
+            ("fragmentation", partially_used.saturating_sub(size_class_rounding)),
+            ("free_pages", free_pages),
+            ("thread_stacks", thread_stacks),
+        ]
+    }
+
+    /// Frames of the stack trace stored in the header, innermost first.
+    pub(crate) fn frames(ah: &AllocHeader) -> &[*mut c_void] {
+        let len = ah
+            .stack()
+            .iter()
+            .take_while(|ptr| ptr.is_null().not() && **ptr != usize::MAX as *mut c_void)
+            .count();
+        &ah.stack()[..len]
+    }
+
+    /// Symbols `ptr` resolves to, one per mapping of the executable containing it.
+    fn resolve_ptr<'a>(
+        ptr: *mut c_void,
+        mmaped_exec: &'a [Smap],
+        symbols: &'a [Symbol],
+    ) -> impl Iterator<Item = &'a Symbol> {
+        (mmaped_exec.iter())
+            .filter(move |x| ((x.from as *mut c_void) <= ptr) && ((ptr as usize) < x.to))
+            .filter_map(move |smap| {
+                let file_offset = (ptr as usize) - smap.from + smap.offset;
+                find_symbol(symbols, file_offset)
+            })
+    }
+
+    /// Name of the function `ptr` points into, as it should be displayed.
+    fn frame_name(&self, ptr: *mut c_void, mmaped_exec: &[Smap], symbols: &[Symbol]) -> String {
+        match Self::resolve_ptr(ptr, mmaped_exec, symbols).next() {
+            Some(sym) if !self.print_ptr => {
+                if self.print_raw_symbols {
+                    sym.raw_symbol.clone()
+                } else {
+                    sym.symbol.clone()
+                }
+            }
+            _ => format!("{:?}", ptr),
+        }
+    }
+
+    /// Memory usage per stack in Brendan Gregg's collapsed format: frames from the outermost to
+    /// the innermost separated by `;`, followed by the number of bytes.
+    fn collapsed_stacks(
+        &self,
+        mmaped_exec: &[Smap],
+        symbols: &[Symbol],
+        stack_2_memory: &HashMap<Vec<*mut c_void>, Counter>,
+    ) -> Vec<String> {
+        let mut folded: HashMap<String, usize> = HashMap::new();
+        for (stack, counter) in stack_2_memory {
+            let frames = stack
+                .iter()
+                .rev()
+                // `;` separates frames, but it can also be part of a type like `[u8; 32]`.
+                .map(|&ptr| self.frame_name(ptr, mmaped_exec, symbols).replace(';', ":"))
+                .join(";");
+            *folded.entry(frames).or_default() += counter.size;
+        }
+        folded.into_iter().map(|(frames, size)| format!("{} {}", frames, size)).sorted().collect()
+    }
+
+    /// Logs the distribution of sizes of all allocations and of sampled ones per function.
+    fn print_size_histograms(
+        &self,
+        mmaped_exec: &[Smap],
+        symbols: &[Symbol],
+        all_sizes: &SizeHistogram,
+        ptr_2_sizes: &HashMap<*mut c_void, SizeHistogram>,
+    ) {
+        let mut func_2_sizes: HashMap<String, SizeHistogram> = HashMap::new();
+        for (ptr, histogram) in ptr_2_sizes {
+            *func_2_sizes.entry(self.frame_name(*ptr, mmaped_exec, symbols)).or_default() +=
+                histogram;
+        }
+        info!("Size histogram");
+        Self::print_size_histogram(all_sizes);
+        let mut func_2_sizes: Vec<_> = func_2_sizes
+            .into_iter()
+            .map(|(func, histogram)| {
+                (
+                    func,
+                    histogram.buckets.values().map(|counter| counter.size).sum::<usize>(),
+                    histogram,
+                )
+            })
+            .filter(|(_, size, _)| *size >= MIB)
+            .collect();
+        func_2_sizes.sort_by_key(|(_, size, _)| *size);
+        for (func, size, histogram) in func_2_sizes {
+            info!(?func, size_mb = size / MIB, "Size histogram");
+            Self::print_size_histogram(&histogram);
+        }
+    }
+
+    fn print_size_histogram(histogram: &SizeHistogram) {
+        for (bucket, counter) in &histogram.buckets {
+            let max_size = SizeHistogram::upper_bound(*bucket);
+            info!(max_size, count = counter.cnt, size = counter.size);
+        }
+    }
+
+    fn write_pprof(
+        path: &Path,
+        smaps: &[Smap],
+        mmaped_exec: &[Smap],
+        symbols: &[Symbol],
+        stack_2_memory: &HashMap<Vec<*mut c_void>, Counter>,
+    ) -> anyhow::Result<()> {
+        let mut builder =
+            ProfileBuilder::new(&[("inuse_objects", "count"), ("inuse_space", "bytes")]);
+        let mut mapping_ids: HashMap<usize, u64> = HashMap::new();
+        let mut build_ids: HashMap<&str, String> = HashMap::new();
+        for (stack, counter) in stack_2_memory {
+            let locations = stack
+                .iter()
+                .map(|&ptr| {
+                    let mapping_id = match smaps
+                        .iter()
+                        .position(|smap| smap.from <= ptr as usize && (ptr as usize) < smap.to)
+                    {
+                        Some(idx) => *mapping_ids.entry(idx).or_insert_with(|| {
+                            let smap = &smaps[idx];
+                            let filename = smap.mapped_file.as_deref().unwrap_or_default();
+                            let build_id = build_ids
+                                .entry(filename)
+                                .or_insert_with(|| read_build_id(filename).unwrap_or_default());
+                            builder.add_mapping(&Mapping {
+                                memory_start: smap.from as u64,
+                                memory_limit: smap.to as u64,
+                                file_offset: smap.offset as u64,
+                                filename: filename.to_string(),
+                                build_id: build_id.clone(),
+                            })
+                        }),
+                        None => 0,
+                    };
+                    let function = Self::resolve_ptr(ptr, mmaped_exec, symbols).next();
+                    builder.location(
+                        ptr as u64,
+                        mapping_id,
+                        function.map(|sym| sym.symbol.as_str()),
+                    )
+                })
+                .collect_vec();
+            builder.add_sample(&locations, &[counter.cnt as i64, counter.size as i64], &[]);
+        }
+        let file = File::create(path)?;
+        builder.write_gzip(BufWriter::new(file))?;
         Ok(())
     }
 
@@ -160,3 +981,179 @@ impl AnalyzeCmd {
         mmaped_exec
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::analyze::{AnalyzeCmd, HeaderCheck, HeaderKind, C_MAGIC};
+    use crate::utils::{parse_smaps, Counter};
+    use near_rust_allocator_proxy::{AllocHeader, ProxyAllocator};
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::collections::HashMap;
+    use std::ffi::c_void;
+
+    #[test]
+    fn test_incremental_requires_consistent() {
+        use clap::Parser;
+        let parse = |args: &[&str]| {
+            AnalyzeCmd::try_parse_from(
+                ["analyze", "--watch", "1", "--incremental"].iter().chain(args),
+            )
+        };
+        assert!(parse(&["--pid", "1"]).is_err());
+        assert!(parse(&["--consistent"]).is_err());
+        assert!(parse(&["--pid", "1", "--consistent"]).is_ok());
+    }
+
+    #[test]
+    fn test_collapsed_stacks() {
+        let cmd = AnalyzeCmd {
+            pid: None,
+            core: None,
+            dump: None,
+            exe: None,
+            print_raw_symbols: false,
+            print_ptr: true,
+            output_pprof: None,
+            collapsed: None,
+            flamegraph: None,
+            size_histogram: false,
+            read_swapped: false,
+            watch: None,
+            incremental: false,
+            consistent: false,
+            freeze_timeout: 10,
+        };
+        let (leaf, caller, other) =
+            (0x1000 as *mut c_void, 0x2000 as *mut c_void, 0x3000 as *mut c_void);
+        let stack_2_memory = HashMap::from([
+            (vec![leaf, caller], Counter { cnt: 2, size: 100 }),
+            (vec![other], Counter { cnt: 1, size: 10 }),
+        ]);
+        assert_eq!(
+            cmd.collapsed_stacks(&[], &[], &stack_2_memory),
+            vec!["0x2000;0x1000 100".to_string(), "0x3000 10".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_unused_breakdown() {
+        let smaps = "\
+10000-12000 rw-p 00000000 00:00 0 
+7fff0000-7fff2000 rw-p 00000000 00:00 0                                  [stack]
+";
+        let smaps = parse_smaps(smaps.lines().map(str::to_string)).unwrap();
+        let mut stack = smaps[1].clone();
+        stack.is_stack = true;
+        let pages =
+            [(smaps[0].clone(), vec![0x10000, 0x11000]), (stack, vec![0x7fff0000, 0x7fff1000])];
+        // One allocation of 100 bytes with its header on the first page, the second is free.
+        let extents = vec![(0x10000, 132)];
+        let breakdown = AnalyzeCmd::unused_breakdown(
+            &pages,
+            extents,
+            0x1000,
+            Counter { cnt: 1, size: 100 },
+            0,
+            12,
+        );
+        let breakdown: HashMap<_, _> = breakdown.into_iter().collect();
+        assert_eq!(breakdown["thread_stacks"], 0x2000);
+        assert_eq!(breakdown["free_pages"], 0x1000);
+        assert_eq!(breakdown["size_class_rounding"], 12);
+        assert_eq!(breakdown["fragmentation"], 0x1000 - 132 - 12);
+        assert_eq!(breakdown["unsampled_allocations"], 100);
+    }
+
+    #[test]
+    fn test_header_check() {
+        let alloc = ProxyAllocator::new(System);
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let (_, offset) = Layout::new::<AllocHeader>().extend(layout).unwrap();
+        let ptr = unsafe { alloc.alloc(layout) };
+        let header = unsafe { ptr.sub(offset) };
+        let words = unsafe { std::slice::from_raw_parts(header.cast::<usize>(), offset / 8) };
+        let ah = unsafe { &*(words.as_ptr() as *const AllocHeader) };
+        let address = header as usize;
+
+        let mut check = HeaderCheck::default();
+        assert!(check.accept(ah, address, address + offset + 100));
+        assert!(!check.accept(ah, address, address + offset + 99));
+        assert_eq!(check.rejected.size, 1);
+
+        // The allocation can continue in the next writable mapping, but not in a guard page.
+        let (from, to) = (address / 4096 * 4096, address + offset + 99);
+        let smaps = [
+            format!("{:x}-{:x} rw-p 00000000 00:00 0", from, to),
+            format!("{:x}-{:x} rw-p 00000000 00:00 0", to, to + 4096),
+            format!("{:x}-{:x} ---p 00000000 00:00 0", to + 4096, to + 8192),
+        ];
+        let mut check = HeaderCheck::new(&parse_smaps(smaps.into_iter()).unwrap());
+        assert!(check.accept(ah, address, to));
+        assert_eq!(check.implausible_field(ah, address, to + 4096), None);
+        assert_eq!(check.implausible_field(ah, to + 4000, to + 4096), Some("size"));
+
+        // A copy of the magic followed by unrelated data.
+        let mut copy = words.to_vec();
+        copy[1] ^= 1;
+        let ah = unsafe { &*(copy.as_ptr() as *const AllocHeader) };
+        assert!(!check.accept(ah, address, usize::MAX));
+        assert_eq!(check.rejected.checksum, 1);
+        unsafe { alloc.dealloc(ptr, layout) };
+    }
+
+    #[test]
+    fn test_c_header() {
+        let mut check = HeaderCheck { executable: vec![(0x1000, 0x2000)], ..Default::default() };
+        let header = |magic: usize, size: usize, func: usize| {
+            [magic, size, 1234, func].iter().flat_map(|word| word.to_ne_bytes()).collect::<Vec<_>>()
+        };
+        let address = 0x10000;
+
+        let found =
+            AnalyzeCmd::find_header(&header(C_MAGIC, 100, 0x1800), address, 0x20000, &mut check)
+                .unwrap();
+        assert_eq!((found.address, found.size, found.kind), (address, 100, HeaderKind::C));
+        assert_eq!(found.stack, vec![0x1800 as *mut c_void]);
+        // Unsampled allocation.
+        let found = AnalyzeCmd::find_header(&header(C_MAGIC, 100, 1), address, 0x20000, &mut check)
+            .unwrap();
+        assert!(found.stack.is_empty());
+
+        // Freed header.
+        assert!(AnalyzeCmd::find_header(
+            &header(C_MAGIC + 0x100, 100, 1),
+            address,
+            0x20000,
+            &mut check
+        )
+        .is_none());
+        // Frame outside of executable mappings, e.g. in an unloaded library.
+        let found =
+            AnalyzeCmd::find_header(&header(C_MAGIC, 100, 0x3000), address, 0x20000, &mut check)
+                .unwrap();
+        assert!(found.stack.is_empty());
+        assert!(AnalyzeCmd::find_header(
+            &header(C_MAGIC, 0x10000, 1),
+            address,
+            0x20000,
+            &mut check
+        )
+        .is_none());
+        assert_eq!((check.unattributed, check.rejected.size, check.rejected.checksum), (1, 1, 0));
+    }
+
+    #[test]
+    fn test_legacy_header() {
+        let mut check = HeaderCheck { executable: vec![(0x1000, 0x2000)], ..Default::default() };
+        // Header of `near-rust-allocator-proxy` before 1.0 with a single frame.
+        let header = |size: usize| {
+            let words = [0x12_3456_7899_1101, size, 1234, 0x1800];
+            words.iter().flat_map(|word: &usize| word.to_ne_bytes()).collect::<Vec<_>>()
+        };
+        let address = 0x10000;
+        let found = AnalyzeCmd::find_header(&header(100), address, 0x20000, &mut check).unwrap();
+        assert_eq!((found.size, found.kind), (100, HeaderKind::Rust));
+        assert!(AnalyzeCmd::find_header(&header(0x10000), address, 0x20000, &mut check).is_none());
+        assert_eq!((check.legacy, check.rejected.size, check.rejected.checksum), (1, 1, 0));
+    }
+}
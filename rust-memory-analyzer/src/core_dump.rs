@@ -0,0 +1,313 @@
+use crate::utils::{mark_stacks, PageMapEntry, Smap};
+use anyhow::Context;
+use nix::errno::Errno;
+use nix::unistd::{lseek, Whence};
+use object::elf::{
+    FileHeader64, EM_AARCH64, EM_X86_64, ET_CORE, NT_FILE, NT_PRSTATUS, PF_R, PF_W, PF_X, PT_LOAD,
+    PT_NOTE,
+};
+use object::read::elf::{FileHeader, ProgramHeader};
+use object::{Endian, Endianness, ReadCache};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use tracing::info;
+
+/// `PT_LOAD` segment of a core dump, there is one for each mapping of the process.
+#[derive(Debug, Clone)]
+struct Segment {
+    vaddr: usize,
+    memsz: usize,
+    /// Offset of the segment's contents in the core file.
+    offset: u64,
+    /// Length of the dumped contents, the rest of the segment is missing. The kernel skips e.g.
+    /// unmodified file mappings.
+    filesz: usize,
+    flags: u32,
+}
+
+/// Mapping of a file as described by the `NT_FILE` note.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MappedFile {
+    from: usize,
+    to: usize,
+    /// Offset in the file in bytes.
+    offset: usize,
+    path: String,
+}
+
+/// ELF core dump of a 64 bit process, e.g. written by the kernel or `gcore`.
+#[derive(Debug)]
+pub struct CoreDump {
+    file: File,
+    segments: Vec<Segment>,
+    files: Vec<MappedFile>,
+    /// Stack pointers of the dumped threads.
+    stack_pointers: Vec<usize>,
+}
+
+impl CoreDump {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path).with_context(|| format!("cant open core dump {:?}", path))?;
+        let cache = ReadCache::new(file.try_clone()?);
+        let header = FileHeader64::<Endianness>::parse(&cache)
+            .map_err(|err| anyhow::anyhow!("not a 64 bit ELF file {:?}: {}", path, err))?;
+        let endian = header.endian().map_err(|err| anyhow::anyhow!("{}", err))?;
+        anyhow::ensure!(header.e_type(endian) == ET_CORE, "not a core dump {:?}", path);
+
+        let machine = header.e_machine(endian);
+        let (mut segments, mut files, mut stack_pointers) = (Vec::new(), Vec::new(), Vec::new());
+        let program_headers =
+            header.program_headers(endian, &cache).map_err(|err| anyhow::anyhow!("{}", err))?;
+        for ph in program_headers {
+            match ph.p_type(endian) {
+                PT_LOAD => segments.push(Segment {
+                    vaddr: ph.p_vaddr(endian) as usize,
+                    memsz: ph.p_memsz(endian) as usize,
+                    offset: ph.p_offset(endian),
+                    filesz: ph.p_filesz(endian) as usize,
+                    flags: ph.p_flags(endian),
+                }),
+                PT_NOTE => {
+                    let mut notes = ph
+                        .notes(endian, &cache)
+                        .map_err(|err| anyhow::anyhow!("{}", err))?
+                        .with_context(|| "PT_NOTE segment without notes")?;
+                    while let Some(note) = notes.next().map_err(|err| anyhow::anyhow!("{}", err))? {
+                        if note.name() == b"CORE" && note.n_type(endian) == NT_FILE {
+                            files = parse_nt_file(note.desc(), endian)?;
+                        }
+                        if note.name() == b"CORE" && note.n_type(endian) == NT_PRSTATUS {
+                            stack_pointers.extend(parse_prstatus_sp(note.desc(), machine, endian));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        info!(
+            segments = segments.len(),
+            files = files.len(),
+            threads = stack_pointers.len(),
+            "Read core dump."
+        );
+        Ok(Self { file, segments, files, stack_pointers })
+    }
+
+    /// Mappings of the process at the time of the dump. Only the fields, which can be derived from
+    /// the dump are set, `Rss` is the size of the dumped contents.
+    pub fn smaps(&self) -> Vec<Smap> {
+        let mut smaps: Vec<Smap> = self
+            .segments
+            .iter()
+            .map(|segment| {
+                let file = self.files.iter().find(|file| file.from == segment.vaddr);
+                let perms = [(PF_R, 'r'), (PF_W, 'w'), (PF_X, 'x')]
+                    .iter()
+                    .map(|&(flag, c)| if segment.flags & flag != 0 { c } else { '-' })
+                    .chain(['p'])
+                    .collect();
+                Smap {
+                    from: segment.vaddr,
+                    to: segment.vaddr + segment.memsz,
+                    perms,
+                    offset: file.map_or(0, |file| file.offset),
+                    dev: "00:00".to_string(),
+                    inode: 0,
+                    mapped_file: file.map(|file| file.path.clone()),
+                    is_stack: false,
+                    fields: BTreeMap::from([("Rss".to_string(), segment.filesz)]),
+                    vm_flags: Vec::new(),
+                }
+            })
+            .collect();
+        mark_stacks(&mut smaps, &self.stack_pointers);
+        smaps
+    }
+
+    /// Address and pagemap entry of each page of `smap`. Dumped pages are reported as present,
+    /// others as neither present nor swapped. The kernel leaves holes in the core file for pages,
+    /// which were never touched, so they aren't present either.
+    pub fn page_map(&self, smap: &Smap, page_size: usize) -> Vec<(usize, PageMapEntry)> {
+        let (offset, dumped) = self.segment(smap.from).map_or((0, 0), |segment| {
+            (segment.offset, segment.filesz.min(smap.to - smap.from) as u64)
+        });
+        let data = self.data_ranges(offset, offset + dumped);
+        (smap.from..smap.to)
+            .step_by(page_size)
+            .map(|address| {
+                let start = offset + (address - smap.from) as u64;
+                let end = (start + page_size as u64).min(offset + dumped);
+                let present = data.iter().any(|&(from, to)| from < end && start < to);
+                (address, PageMapEntry(u64::from(present) << 63))
+            })
+            .collect()
+    }
+
+    /// Ranges of the core file between `from` and `to`, which aren't holes. The whole range, if the
+    /// file system doesn't support finding holes.
+    fn data_ranges(&self, from: u64, to: u64) -> Vec<(u64, u64)> {
+        let fd = self.file.as_raw_fd();
+        let mut res = Vec::new();
+        let mut pos = from;
+        while pos < to {
+            let start = match lseek(fd, pos as i64, Whence::SeekData) {
+                Ok(start) => start as u64,
+                // No data after `pos`.
+                Err(Errno::ENXIO) => break,
+                Err(_) => return vec![(from, to)],
+            };
+            let end = lseek(fd, start as i64, Whence::SeekHole).map_or(to, |end| end as u64);
+            if start < to {
+                res.push((start, end.min(to)));
+            }
+            pos = end;
+        }
+        res
+    }
+
+    /// Fills `buffer` with memory starting at `address`, memory which wasn't dumped reads as
+    /// zeros. The range must not span multiple segments.
+    pub fn read(&self, address: usize, buffer: &mut [u8]) -> anyhow::Result<()> {
+        let segment = (self.segment(address))
+            .with_context(|| format!("address {:#x} isn't part of the core dump", address))?;
+        let start = address - segment.vaddr;
+        let dumped = segment.filesz.saturating_sub(start).min(buffer.len());
+        self.file
+            .read_exact_at(&mut buffer[..dumped], segment.offset + start as u64)
+            .with_context(|| format!("failed to read {:#x} from the core dump", address))?;
+        buffer[dumped..].fill(0);
+        Ok(())
+    }
+
+    fn segment(&self, address: usize) -> Option<&Segment> {
+        (self.segments.iter())
+            .find(|segment| segment.vaddr <= address && address < segment.vaddr + segment.memsz)
+    }
+}
+
+/// Parses the description of a `NT_FILE` note: the number of mappings and the page size followed
+/// by `(start, end, offset in pages)` of each mapping and their null terminated paths.
+fn parse_nt_file(desc: &[u8], endian: Endianness) -> anyhow::Result<Vec<MappedFile>> {
+    let word = |idx: usize| -> anyhow::Result<usize> {
+        let bytes = desc.get(idx * 8..idx * 8 + 8).with_context(|| "NT_FILE note is truncated")?;
+        Ok(endian.read_u64_bytes(bytes.try_into().unwrap()) as usize)
+    };
+    let (count, page_size) = (word(0)?, word(1)?);
+    anyhow::ensure!(count <= desc.len() / 24, "NT_FILE note is truncated");
+    let paths = desc.get((2 + 3 * count) * 8..).with_context(|| "NT_FILE note is truncated")?;
+    let mut paths = paths.split(|&b| b == 0);
+    (0..count)
+        .map(|idx| {
+            let path = paths.next().with_context(|| "NT_FILE note is truncated")?;
+            Ok(MappedFile {
+                from: word(2 + 3 * idx)?,
+                to: word(3 + 3 * idx)?,
+                offset: word(4 + 3 * idx)? * page_size,
+                path: String::from_utf8_lossy(path).into_owned(),
+            })
+        })
+        .collect()
+}
+
+/// Stack pointer in the description of a `NT_PRSTATUS` note, a `struct elf_prstatus`. Its
+/// registers start at offset 112 and the stack pointer is `rsp` on x86_64 and `sp` on aarch64.
+fn parse_prstatus_sp(desc: &[u8], machine: u16, endian: Endianness) -> Option<usize> {
+    let register = match machine {
+        EM_X86_64 => 19,
+        EM_AARCH64 => 31,
+        _ => return None,
+    };
+    let offset = 112 + register * 8;
+    let bytes = desc.get(offset..offset + 8)?;
+    Some(endian.read_u64_bytes(bytes.try_into().unwrap()) as usize)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core_dump::CoreDump;
+    use std::fs;
+
+    /// Builds a core dump with `NT_FILE` and `NT_PRSTATUS` notes and two `PT_LOAD` segments: a
+    /// mapped file, which wasn't dumped, and a dumped anonymous page holding a thread stack.
+    fn core_dump() -> Vec<u8> {
+        let words = |words: &[u64]| words.iter().flat_map(|w| w.to_le_bytes()).collect::<Vec<_>>();
+        let mut desc = words(&[1, 0x1000, 0x400000, 0x401000, 2]);
+        desc.extend(b"/bin/test\0\0\0\0\0\0\0");
+        let mut note = [5u32, desc.len() as u32, 0x4649_4c45]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect::<Vec<_>>();
+        note.extend(b"CORE\0\0\0\0");
+        note.extend(&desc);
+        // `struct elf_prstatus` of x86_64 with `rsp` set.
+        let mut prstatus = vec![0u8; 336];
+        prstatus[264..272].copy_from_slice(&0x600800u64.to_le_bytes());
+        note.extend([5u32, prstatus.len() as u32, 1].iter().flat_map(|w| w.to_le_bytes()));
+        note.extend(b"CORE\0\0\0\0");
+        note.extend(&prstatus);
+
+        let (phoff, phnum) = (64, 3);
+        let note_offset = phoff + phnum * 56;
+        let data_offset = note_offset + note.len() as u64;
+        let mut res = b"\x7fELF\x02\x01\x01".to_vec();
+        res.resize(16, 0);
+        res.extend(4u16.to_le_bytes()); // e_type
+        res.extend(62u16.to_le_bytes()); // e_machine
+        res.extend(1u32.to_le_bytes()); // e_version
+        res.extend(words(&[0, phoff, 0])); // e_entry, e_phoff, e_shoff
+        res.extend(0u32.to_le_bytes()); // e_flags
+        for half in [64u16, 56, phnum as u16, 64, 0, 0] {
+            res.extend(half.to_le_bytes());
+        }
+        // p_type, p_flags, p_offset, p_vaddr, p_paddr, p_filesz, p_memsz, p_align
+        let phdr = |p_type: u32, p_flags: u32, words_: [u64; 6]| {
+            let mut res =
+                [p_type, p_flags].iter().flat_map(|w| w.to_le_bytes()).collect::<Vec<_>>();
+            res.extend(words(&words_));
+            res
+        };
+        let note_len = note.len() as u64;
+        res.extend(phdr(4, 0, [note_offset, 0, 0, note_len, 0, 4]));
+        res.extend(phdr(1, 5, [data_offset, 0x400000, 0, 0, 0x1000, 0x1000]));
+        res.extend(phdr(1, 6, [data_offset, 0x600000, 0, 0x1000, 0x2000, 0x1000]));
+        res.extend(note);
+        res.extend((0..0x1000).map(|i| i as u8));
+        res
+    }
+
+    #[test]
+    fn test_core_dump() {
+        let path = std::env::temp_dir().join(format!("core_dump_test.{}", std::process::id()));
+        fs::write(&path, core_dump()).unwrap();
+        let core = CoreDump::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let smaps = core.smaps();
+        assert_eq!(smaps.len(), 2);
+        assert_eq!((smaps[0].from, smaps[0].to, smaps[0].offset), (0x400000, 0x401000, 0x2000));
+        assert_eq!(smaps[0].mapped_file.as_deref(), Some("/bin/test"));
+        assert_eq!(smaps[0].perms, "r-xp");
+        assert_eq!((smaps[1].mapped_file.as_deref(), smaps[1].perms.as_str()), (None, "rw-p"));
+        assert!(!smaps[0].is_stack && smaps[1].is_stack);
+
+        let present = |idx: usize| {
+            core.page_map(&smaps[idx], 0x1000)
+                .iter()
+                .map(|(_, entry)| entry.present())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(present(0), vec![false]);
+        assert_eq!(present(1), vec![true, false]);
+
+        let mut buffer = [0xffu8; 16];
+        core.read(0x600ff8, &mut buffer).unwrap();
+        assert_eq!(
+            buffer,
+            [0xf8, 0xf9, 0xfa, 0xfb, 0xfc, 0xfd, 0xfe, 0xff, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert!(core.read(0x500000, &mut buffer).is_err());
+    }
+}
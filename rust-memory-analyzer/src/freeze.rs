@@ -0,0 +1,113 @@
+use anyhow::Context;
+use nix::errno::Errno;
+use nix::sys::ptrace;
+use nix::sys::wait::{waitpid, WaitPidFlag};
+use nix::unistd::Pid;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Keeps all threads of a process stopped until dropped, so that its memory doesn't change while
+/// being read.
+///
+/// Threads are attached with `PTRACE_SEIZE` and stopped with `PTRACE_INTERRUPT`. Unlike `SIGSTOP`,
+/// this can't leave the process stopped: if the analyzer exits for any reason, including Ctrl-C or
+/// `SIGKILL`, the kernel detaches all threads and they continue running.
+#[derive(Debug)]
+pub struct Freeze {
+    tids: Vec<Pid>,
+    start: Instant,
+    timeout: Duration,
+}
+
+impl Freeze {
+    pub fn new(pid: i32, timeout: Duration) -> anyhow::Result<Self> {
+        let mut freeze = Self { tids: Vec::new(), start: Instant::now(), timeout };
+        // Threads can be created until all existing ones are stopped.
+        loop {
+            let new: Vec<_> =
+                task_ids(pid)?.into_iter().filter(|tid| !freeze.tids.contains(tid)).collect();
+            if new.is_empty() {
+                break;
+            }
+            for tid in new {
+                match ptrace::seize(tid, ptrace::Options::empty()) {
+                    Ok(()) => freeze.tids.push(tid),
+                    // The thread already exited.
+                    Err(Errno::ESRCH) => continue,
+                    Err(err) => {
+                        return Err(err).with_context(|| format!("failed to attach to {}", tid))
+                    }
+                }
+                ptrace::interrupt(tid).with_context(|| format!("failed to stop {}", tid))?;
+                waitpid(tid, Some(WaitPidFlag::__WALL))
+                    .with_context(|| format!("failed to wait for {} to stop", tid))?;
+            }
+        }
+        info!(threads = freeze.tids.len(), took = ?freeze.start.elapsed(), "Froze process.");
+        freeze.start = Instant::now();
+        Ok(freeze)
+    }
+
+    /// Fails once the process has been frozen for longer than the timeout.
+    pub fn check(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.start.elapsed() <= self.timeout,
+            "process was frozen for longer than {:?}, resuming it",
+            self.timeout
+        );
+        Ok(())
+    }
+}
+
+impl Drop for Freeze {
+    fn drop(&mut self) {
+        for tid in &self.tids {
+            if let Err(err) = ptrace::detach(*tid, None) {
+                warn!(?tid, ?err, "Failed to resume thread.");
+            }
+        }
+        info!(frozen_for = ?self.start.elapsed(), "Resumed process.");
+    }
+}
+
+/// Ids of all threads of `pid`.
+fn task_ids(pid: i32) -> anyhow::Result<Vec<Pid>> {
+    let task = PathBuf::from("/proc").join(pid.to_string()).join("task");
+    let mut res = Vec::new();
+    for entry in fs::read_dir(&task).with_context(|| format!("cant list threads {:?}", task))? {
+        if let Ok(tid) = entry?.file_name().to_string_lossy().parse() {
+            res.push(Pid::from_raw(tid));
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::freeze::Freeze;
+    use std::fs;
+    use std::process::Command;
+    use std::time::Duration;
+
+    #[test]
+    fn test_freeze() {
+        let mut child = Command::new("sleep").arg("60").spawn().unwrap();
+        let pid = child.id() as i32;
+        let state = || {
+            let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap();
+            status.lines().find(|line| line.starts_with("State:")).unwrap().to_string()
+        };
+
+        let freeze = Freeze::new(pid, Duration::from_secs(10)).unwrap();
+        assert!(state().contains("tracing stop"), "{}", state());
+        freeze.check().unwrap();
+        drop(freeze);
+        assert!(!state().contains("tracing stop"), "{}", state());
+
+        assert!(Freeze::new(pid, Duration::ZERO).unwrap().check().is_err());
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
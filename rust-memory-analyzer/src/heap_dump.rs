@@ -0,0 +1,385 @@
+use crate::utils::{
+    get_page_size, mark_stacks, parse_smaps, read_build_id, read_page_map, thread_stack_pointers,
+    PageMapEntry, Smap, MIB,
+};
+use anyhow::Context;
+use nix::sys::uio::{IoVec, RemoteIoVec};
+use nix::unistd::Pid;
+use std::cell::{Ref, RefCell};
+use std::ffi::OsStr;
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tracing::{info, warn};
+
+const MAGIC: &[u8; 8] = b"RMAHEAP\0";
+const VERSION: u64 = 2;
+/// Uncompressed size of the frames stored pages are compressed in, reading a page only needs to
+/// decompress its frame.
+const FRAME_SIZE: usize = 1 << 20;
+
+/// Writes present anonymous pages of a process into a compact heap dump, which can be analyzed
+/// later with `--dump`.
+#[derive(clap_derive::Parser, Debug)]
+pub(crate) struct DumpCmd {
+    #[clap(long)]
+    pid: i32,
+    #[clap(long)]
+    out: PathBuf,
+    /// zstd compression level.
+    #[clap(long, default_value = "3")]
+    level: i32,
+}
+
+impl DumpCmd {
+    /// Layout of the dump, integers are little endian `u64`s and blobs are prefixed by their
+    /// length:
+    /// * magic, version, page size, pid
+    /// * executable path, build id
+    /// * zstd compressed contents of `/proc/<pid>/smaps`
+    /// * number of thread stack pointers, stack pointers
+    /// * number of regions, for each mapping of the process:
+    ///   * start, end
+    ///   * zstd compressed pagemap entries of all pages
+    ///   * number of frames, for anonymous mappings the contents of present pages in frames of
+    ///     `FRAME_SIZE` bytes, each compressed on its own, none for others
+    pub(crate) fn handle(&self) -> anyhow::Result<()> {
+        info!(?self.pid, ?self.out);
+        let start = Instant::now();
+        let proc = PathBuf::from("/proc").join(self.pid.to_string());
+        let smaps_text = fs::read_to_string(proc.join("smaps"))
+            .with_context(|| format!("cant read smaps of {}", self.pid))?;
+        let smaps = parse_smaps(smaps_text.lines().map(str::to_string))?;
+        let exe_path =
+            fs::read_link(proc.join("exe")).with_context(|| "unable to read exe path")?;
+        let build_id = read_build_id(&exe_path).unwrap_or_default();
+        info!(?exe_path, ?build_id);
+        let page_size = get_page_size()?;
+        let mut pagemap = File::open(proc.join("pagemap"))
+            .with_context(|| format!("cant open pagemap of {}", self.pid))?;
+
+        let mut out = BufWriter::new(
+            File::create(&self.out).with_context(|| format!("cant create {:?}", self.out))?,
+        );
+        out.write_all(MAGIC)?;
+        for value in [VERSION, page_size as u64, self.pid as u64] {
+            write_u64(&mut out, value)?;
+        }
+        write_bytes(&mut out, exe_path.as_os_str().as_bytes())?;
+        write_bytes(&mut out, build_id.as_bytes())?;
+        write_compressed(&mut out, self.level, |w| Ok(w.write_all(smaps_text.as_bytes())?))?;
+        let stack_pointers = thread_stack_pointers(self.pid);
+        write_u64(&mut out, stack_pointers.len() as u64)?;
+        for sp in stack_pointers {
+            write_u64(&mut out, sp as u64)?;
+        }
+
+        write_u64(&mut out, smaps.len() as u64)?;
+        let (mut present_pages, mut failed_pages) = (0, 0);
+        let mut buffer = vec![0u8; page_size];
+        for smap in &smaps {
+            let entries = read_page_map(smap, &mut pagemap, page_size)?;
+            write_u64(&mut out, smap.from as u64)?;
+            write_u64(&mut out, smap.to as u64)?;
+            write_compressed(&mut out, self.level, |w| {
+                for (_, entry) in &entries {
+                    w.write_all(&entry.0.to_le_bytes())?;
+                }
+                Ok(())
+            })?;
+            let present: Vec<usize> = if stores_contents(smap) {
+                entries
+                    .iter()
+                    .filter(|(_, entry)| entry.present())
+                    .map(|(address, _)| *address)
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            let frames: Vec<&[usize]> = present.chunks(frame_pages(page_size)).collect();
+            write_u64(&mut out, frames.len() as u64)?;
+            for frame in frames {
+                write_compressed(&mut out, self.level, |w| {
+                    for address in frame {
+                        let input = [IoVec::from_mut_slice(&mut buffer)];
+                        let output = [RemoteIoVec { base: *address, len: page_size }];
+                        // The page can be unmapped after reading pagemap.
+                        if nix::sys::uio::process_vm_readv(Pid::from_raw(self.pid), &input, &output)
+                            .is_err()
+                        {
+                            failed_pages += 1;
+                            buffer.fill(0);
+                        }
+                        w.write_all(&buffer)?;
+                        present_pages += 1;
+                    }
+                    Ok(())
+                })?;
+            }
+        }
+        out.flush()?;
+        if failed_pages > 0 {
+            warn!(failed_pages, "Some pages couldn't be read, they are stored as zeros.");
+        }
+        let size = out.get_ref().metadata()?.len() as usize;
+        info!(
+            present_mb = present_pages * page_size / MIB,
+            dump_mb = size / MIB,
+            took = ?start.elapsed(),
+            "Wrote heap dump."
+        );
+        Ok(())
+    }
+}
+
+/// Number of pages in a frame of stored pages.
+fn frame_pages(page_size: usize) -> usize {
+    (FRAME_SIZE / page_size).max(1)
+}
+
+/// Anonymous mappings including the heap and stacks, but not e.g. `[vdso]`.
+fn stores_contents(smap: &Smap) -> bool {
+    smap.inode == 0
+        && (smap.is_anonymous()
+            || smap.mapped_file.as_deref().map_or(false, |f| !f.starts_with('[')))
+}
+
+fn write_u64(out: &mut impl Write, value: u64) -> anyhow::Result<()> {
+    Ok(out.write_all(&value.to_le_bytes())?)
+}
+
+fn write_bytes(out: &mut impl Write, bytes: &[u8]) -> anyhow::Result<()> {
+    write_u64(out, bytes.len() as u64)?;
+    Ok(out.write_all(bytes)?)
+}
+
+/// Writes a length prefixed blob of data compressed with zstd. The length is filled in
+/// afterwards, so that the data doesn't have to be kept in memory.
+fn write_compressed(
+    out: &mut BufWriter<File>,
+    level: i32,
+    write: impl FnOnce(&mut dyn Write) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let len_pos = out.stream_position()?;
+    write_u64(out, 0)?;
+    let mut encoder = zstd::Encoder::new(&mut *out, level)?;
+    write(&mut encoder)?;
+    encoder.finish()?;
+    let end = out.stream_position()?;
+    out.seek(SeekFrom::Start(len_pos))?;
+    write_u64(out, end - len_pos - 8)?;
+    out.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+/// Mapping stored in a heap dump.
+#[derive(Debug)]
+struct Region {
+    from: usize,
+    entries: Vec<PageMapEntry>,
+    /// Addresses of pages stored in the dump in ascending order.
+    stored: Vec<usize>,
+    /// Offset and length of the compressed frames holding the contents of `stored` pages.
+    frames: Vec<(u64, u64)>,
+}
+
+/// Heap dump written by `dump`.
+#[derive(Debug)]
+pub struct HeapDump {
+    pub page_size: usize,
+    pub pid: i32,
+    pub exe_path: PathBuf,
+    pub build_id: String,
+    smaps: Vec<Smap>,
+    regions: Vec<Region>,
+    file: File,
+    /// Index of the region and of the frame and decompressed contents of the last frame read from.
+    cache: RefCell<Option<(usize, usize, Vec<u8>)>>,
+}
+
+impl HeapDump {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path).with_context(|| format!("cant open heap dump {:?}", path))?;
+        let mut reader = BufReader::new(file.try_clone()?);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        anyhow::ensure!(&magic == MAGIC, "not a heap dump {:?}", path);
+        let version = read_u64(&mut reader)?;
+        anyhow::ensure!(version == VERSION, "unsupported heap dump version {}", version);
+        let page_size = read_u64(&mut reader)? as usize;
+        let pid = read_u64(&mut reader)? as i32;
+        let exe_path = PathBuf::from(OsStr::from_bytes(&read_bytes(&mut reader)?));
+        let build_id = String::from_utf8(read_bytes(&mut reader)?)?;
+        let smaps_text = String::from_utf8(zstd::decode_all(&read_bytes(&mut reader)?[..])?)?;
+        let mut smaps = parse_smaps(smaps_text.lines().map(str::to_string))?;
+        let stack_pointers = (0..read_u64(&mut reader)?)
+            .map(|_| Ok(read_u64(&mut reader)? as usize))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        mark_stacks(&mut smaps, &stack_pointers);
+
+        let mut regions = Vec::new();
+        for _ in 0..read_u64(&mut reader)? {
+            let from = read_u64(&mut reader)? as usize;
+            let _to = read_u64(&mut reader)?;
+            let entries: Vec<_> = zstd::decode_all(&read_bytes(&mut reader)?[..])?
+                .chunks_exact(8)
+                .map(|entry| PageMapEntry(u64::from_le_bytes(entry.try_into().unwrap())))
+                .collect();
+            let mut frames = Vec::new();
+            for _ in 0..read_u64(&mut reader)? {
+                let len = read_u64(&mut reader)?;
+                frames.push((reader.stream_position()?, len));
+                reader.seek(SeekFrom::Current(len as i64))?;
+            }
+            let stored = if !frames.is_empty()
+                && smaps.iter().any(|s| s.from == from && stores_contents(s))
+            {
+                (entries.iter().enumerate())
+                    .filter(|(_, entry)| entry.present())
+                    .map(|(idx, _)| from + idx * page_size)
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            regions.push(Region { from, entries, stored, frames });
+        }
+        info!(?pid, ?exe_path, regions = regions.len(), "Read heap dump.");
+        Ok(Self {
+            page_size,
+            pid,
+            exe_path,
+            build_id,
+            smaps,
+            regions,
+            file,
+            cache: RefCell::new(None),
+        })
+    }
+
+    /// Mappings of the process at the time of the dump.
+    pub fn smaps(&self) -> Vec<Smap> {
+        self.smaps.clone()
+    }
+
+    /// Address and pagemap entry of each page of `smap` at the time of the dump.
+    pub fn page_map(&self, smap: &Smap) -> Vec<(usize, PageMapEntry)> {
+        (self.regions.iter().find(|region| region.from == smap.from))
+            .map(|region| {
+                (region.entries.iter().enumerate())
+                    .map(|(idx, entry)| (region.from + idx * self.page_size, *entry))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Fills `buffer` with memory starting at `address`, pages which weren't stored read as zeros.
+    pub fn read(&self, address: usize, buffer: &mut [u8]) -> anyhow::Result<()> {
+        let idx = (self.regions.iter())
+            .position(|region| {
+                region.from <= address
+                    && address < region.from + region.entries.len() * self.page_size
+            })
+            .with_context(|| format!("address {:#x} isn't part of the heap dump", address))?;
+        let region = &self.regions[idx];
+        let frame_pages = frame_pages(self.page_size);
+        let mut pos = 0;
+        while pos < buffer.len() {
+            let address = address + pos;
+            let page = address - address % self.page_size;
+            let len = (page + self.page_size - address).min(buffer.len() - pos);
+            match region.stored.binary_search(&page) {
+                Ok(stored) => {
+                    let contents = self.frame(idx, stored / frame_pages)?;
+                    let start = stored % frame_pages * self.page_size + address - page;
+                    buffer[pos..pos + len].copy_from_slice(&contents[start..start + len]);
+                }
+                Err(_) => buffer[pos..pos + len].fill(0),
+            }
+            pos += len;
+        }
+        Ok(())
+    }
+
+    /// Decompressed contents of frame `frame` of region `region`, cached until another frame is
+    /// read.
+    fn frame(&self, region: usize, frame: usize) -> anyhow::Result<Ref<'_, [u8]>> {
+        let cached = self.cache.borrow().as_ref().map(|(region, frame, _)| (*region, *frame));
+        if cached != Some((region, frame)) {
+            let (offset, len) = *(self.regions[region].frames.get(frame))
+                .with_context(|| format!("frame {} of region {} is missing", frame, region))?;
+            let mut compressed = vec![0u8; len as usize];
+            self.file.read_exact_at(&mut compressed, offset)?;
+            *self.cache.borrow_mut() = Some((region, frame, zstd::decode_all(&compressed[..])?));
+        }
+        Ok(Ref::map(self.cache.borrow(), |cache| &cache.as_ref().unwrap().2[..]))
+    }
+}
+
+fn read_u64(reader: &mut impl Read) -> anyhow::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_bytes(reader: &mut impl Read) -> anyhow::Result<Vec<u8>> {
+    let len = read_u64(reader)?;
+    let mut bytes = Vec::new();
+    reader.take(len).read_to_end(&mut bytes)?;
+    anyhow::ensure!(bytes.len() as u64 == len, "heap dump is truncated");
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::heap_dump::{stores_contents, DumpCmd, HeapDump, FRAME_SIZE};
+    use crate::utils::{parse_smaps, PageMapEntry};
+    use std::fs;
+
+    #[test]
+    fn test_heap_dump() {
+        // Dump this process, `value` is on a present anonymous page.
+        let value = Box::new(0x1234_5678_9abc_def0u64);
+        let address = &*value as *const u64 as usize;
+        // Stored in several frames.
+        let large: Vec<u8> = (0..3 * FRAME_SIZE).map(|i| (i % 251) as u8).collect();
+        let path = std::env::temp_dir().join(format!("heap_dump_test.{}", std::process::id()));
+        let cmd = DumpCmd { pid: std::process::id() as i32, out: path.clone(), level: 1 };
+        cmd.handle().unwrap();
+        let dump = HeapDump::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(dump.pid, std::process::id() as i32);
+        let smaps = dump.smaps();
+        let smap = smaps.iter().find(|smap| smap.from <= address && address < smap.to).unwrap();
+        let entries = dump.page_map(smap);
+        assert_eq!(entries.len(), (smap.to - smap.from) / dump.page_size);
+        assert!(entries.iter().any(|(_, entry)| PageMapEntry::present(*entry)));
+
+        let mut buffer = [0u8; 8];
+        dump.read(address, &mut buffer).unwrap();
+        assert_eq!(u64::from_ne_bytes(buffer), *value);
+        for offset in [0, FRAME_SIZE - 4, 2 * FRAME_SIZE + 100, 3 * FRAME_SIZE - 8] {
+            dump.read(large.as_ptr() as usize + offset, &mut buffer).unwrap();
+            assert_eq!(buffer, large[offset..offset + 8]);
+        }
+    }
+
+    #[test]
+    fn test_stores_contents() {
+        let smaps = "\
+00400000-00452000 rw-p 00000000 08:02 173521                             /usr/bin/test
+00652000-00653000 rw-p 00000000 00:00 0 
+01000000-01100000 rw-p 00000000 00:00 0                                  [heap]
+7fff0f3a9000-7fff0f3ca000 rw-p 00000000 00:00 0                          [stack]
+7fff0f3fc000-7fff0f3fe000 r-xp 00000000 00:00 0                          [vdso]
+";
+        let smaps = parse_smaps(smaps.lines().map(str::to_string)).unwrap();
+        let stored: Vec<_> = smaps.iter().map(stores_contents).collect();
+        // Files can be read from disk, the contents of `[vdso]` are the same in every process.
+        assert_eq!(stored, vec![false, true, true, true, false]);
+    }
+}
@@ -0,0 +1,420 @@
+use crate::analyze::{HeaderCheck, Target};
+use crate::core_dump::CoreDump;
+use crate::freeze::Freeze;
+use crate::heap_dump::HeapDump;
+use crate::utils::{get_page_size, read_smaps, PageMapEntry, Smap};
+use anyhow::Context;
+use near_rust_allocator_proxy::AllocHeader;
+use std::collections::HashMap;
+use std::fs::File;
+use std::mem::size_of;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+const HEADER_SIZE: usize = size_of::<AllocHeader>();
+
+#[derive(clap_derive::Parser, Debug)]
+pub(crate) struct IntegrityCmd {
+    #[clap(long, required_unless_present_any(&["core", "dump"]))]
+    pid: Option<i32>,
+    /// Check an ELF core dump instead of a running process.
+    #[clap(long, conflicts_with_all(&["pid", "dump"]))]
+    core: Option<PathBuf>,
+    /// Check a heap dump written by `dump` instead of a running process.
+    #[clap(long, conflicts_with("pid"))]
+    dump: Option<PathBuf>,
+    /// Stop all threads of the process while its memory is read, otherwise allocations made or
+    /// freed during the scan can show up as problems.
+    #[clap(long, conflicts_with_all(&["core", "dump"]))]
+    consistent: bool,
+    /// Maximum number of seconds the process is kept stopped with `--consistent`. By default 10
+    /// plus 2 for each GiB of resident anonymous memory, which is scanned twice.
+    #[clap(long, requires("consistent"))]
+    freeze_timeout: Option<u64>,
+    /// Maximum number of problems of each kind, which are logged individually.
+    #[clap(long, default_value = "20")]
+    max_reported: usize,
+}
+
+/// Word matching the header magic, which isn't a valid header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct InvalidHeader {
+    address: usize,
+    /// `checksum` if the checksum of an allocated header doesn't match the other fields, otherwise
+    /// the impossible field.
+    field: &'static str,
+}
+
+/// Pointer to the user region of a freed allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DanglingPointer {
+    /// Address of the pointer.
+    address: usize,
+    /// Header of the freed allocation.
+    freed: usize,
+    /// Header of the live allocation containing the pointer, `None` if it's on a thread stack.
+    owner: Option<usize>,
+}
+
+/// Findings of a scan of all pages, filled in two passes: headers first, then pointers.
+#[derive(Debug)]
+struct Integrity {
+    check: HeaderCheck,
+    invalid: Vec<InvalidHeader>,
+    /// `(header, size)` of live allocations.
+    live: Vec<(usize, usize)>,
+    /// Header of each freed allocation by the address of its user region.
+    freed: HashMap<usize, usize>,
+    /// `(header, header)` of live allocations, where the second starts inside of the first.
+    overlaps: Vec<(usize, usize)>,
+    dangling: Vec<DanglingPointer>,
+    /// Number of headers written by `near-rust-allocator-proxy` before 1.0, which have no checksum.
+    legacy: usize,
+}
+
+impl IntegrityCmd {
+    pub(crate) fn handle(&self) -> anyhow::Result<()> {
+        let start = Instant::now();
+        let freeze = match self.pid {
+            Some(pid) if self.consistent => {
+                let timeout = match self.freeze_timeout {
+                    Some(timeout) => Duration::from_secs(timeout),
+                    None => default_freeze_timeout(pid)?,
+                };
+                Some(Freeze::new(pid, timeout)?)
+            }
+            _ => None,
+        };
+        let (smaps, mut target) = match (&self.core, &self.dump, self.pid) {
+            (Some(core), _, _) => {
+                let core = CoreDump::open(core)?;
+                (core.smaps(), Target::Core(core))
+            }
+            (None, Some(dump), _) => {
+                let dump = HeapDump::open(dump)?;
+                (dump.smaps(), Target::Dump(dump))
+            }
+            (None, None, Some(pid)) => {
+                let smaps = read_smaps(pid).with_context(|| "read_smaps failed")?;
+                let page_map_file = PathBuf::from("/proc").join(pid.to_string()).join("pagemap");
+                let pagemap = File::open(page_map_file.clone())
+                    .with_context(|| format!("page_map_file not found file={:?}", page_map_file))?;
+                (smaps, Target::Process { pid, pagemap })
+            }
+            (None, None, None) => anyhow::bail!("either --pid, --core or --dump is required"),
+        };
+        let page_size = match &target {
+            Target::Dump(dump) => dump.page_size,
+            _ => get_page_size()?,
+        };
+        let pages = target.compute_pages(&smaps, page_size, false, PageMapEntry::present)?;
+
+        let mut integrity = Integrity::new(&smaps);
+        let mut buffer = vec![0usize; page_size / size_of::<usize>()];
+        info!("Looking for headers.");
+        for_each_page(&target, &pages, &mut buffer, freeze.as_ref(), |smap, address, page| {
+            integrity.scan_headers(&target, smap, address, page)
+        })?;
+        integrity.find_overlaps();
+        info!("Looking for pointers to freed allocations.");
+        for_each_page(&target, &pages, &mut buffer, freeze.as_ref(), |smap, address, page| {
+            integrity.scan_pointers(smap, address, page);
+            Ok(())
+        })?;
+        drop(freeze);
+
+        info!(
+            live = integrity.live.len(),
+            freed = integrity.freed.len(),
+            took = ?start.elapsed(),
+            "Scanned headers."
+        );
+        if integrity.legacy != 0 {
+            warn!(
+                legacy = integrity.legacy,
+                "Found headers of near-rust-allocator-proxy before 1.0, they have no checksum."
+            );
+        }
+        for invalid in integrity.invalid.iter().take(self.max_reported) {
+            let address = invalid.address as *const u8;
+            warn!(?address, field = invalid.field, "Invalid header.");
+        }
+        for (outer, inner) in integrity.overlaps.iter().take(self.max_reported) {
+            let (outer, inner) = (*outer as *const u8, *inner as *const u8);
+            warn!(?outer, ?inner, "Overlapping allocations.");
+        }
+        for dangling in integrity.dangling.iter().take(self.max_reported) {
+            warn!(
+                address = ?(dangling.address as *const u8),
+                freed = ?(dangling.freed as *const u8),
+                owner = ?dangling.owner.map(|owner| owner as *const u8),
+                "Pointer to a freed allocation, a use after free or stale data."
+            );
+        }
+        // Pointers to freed allocations are often stale data in unused capacity, e.g. of a `Vec`
+        // which was truncated, so they don't fail the check.
+        let problems = integrity.invalid.len() + integrity.overlaps.len();
+        info!(
+            invalid_headers = integrity.invalid.len(),
+            overlapping_allocations = integrity.overlaps.len(),
+            pointers_to_freed = integrity.dangling.len(),
+            "Integrity check done."
+        );
+        if problems != 0 && self.pid.is_some() && !self.consistent {
+            warn!("The process kept running during the scan, use --consistent to rule that out.");
+        }
+        anyhow::ensure!(problems == 0, "found {} heap integrity problems", problems);
+        Ok(())
+    }
+}
+
+/// Time a scan of `pid` is allowed to take, based on the amount of anonymous memory before it's
+/// stopped.
+fn default_freeze_timeout(pid: i32) -> anyhow::Result<Duration> {
+    let smaps = read_smaps(pid).with_context(|| "read_smaps failed")?;
+    let anonymous: usize = smaps.iter().filter(|smap| smap.is_anonymous()).map(Smap::rss).sum();
+    Ok(Duration::from_secs(10 + 2 * (anonymous >> 30) as u64))
+}
+
+/// Reads each page into `buffer` and calls `f` with its mapping, address and contents.
+fn for_each_page(
+    target: &Target,
+    pages: &[(Smap, Vec<usize>)],
+    buffer: &mut [usize],
+    freeze: Option<&Freeze>,
+    mut f: impl FnMut(&Smap, usize, &[usize]) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    for (smap, addresses) in pages {
+        for &address in addresses {
+            if let Some(freeze) = freeze {
+                freeze.check()?;
+            }
+            target.read(address, as_bytes_mut(buffer))?;
+            f(smap, address, buffer)?;
+        }
+    }
+    Ok(())
+}
+
+fn as_bytes_mut(words: &mut [usize]) -> &mut [u8] {
+    unsafe { std::slice::from_raw_parts_mut(words.as_mut_ptr().cast(), words.len() * 8) }
+}
+
+impl Integrity {
+    fn new(smaps: &[Smap]) -> Self {
+        Self {
+            check: HeaderCheck::new(smaps),
+            invalid: Vec::new(),
+            live: Vec::new(),
+            freed: HashMap::new(),
+            overlaps: Vec::new(),
+            dangling: Vec::new(),
+            legacy: 0,
+        }
+    }
+
+    /// Records headers on the page at `address`, `target` is read for headers crossing its end.
+    fn scan_headers(
+        &mut self,
+        target: &Target,
+        smap: &Smap,
+        address: usize,
+        page: &[usize],
+    ) -> anyhow::Result<()> {
+        if smap.is_stack {
+            // Headers are built on the stack before being sealed and written to the heap.
+            return Ok(());
+        }
+        let mut crossing = [0usize; HEADER_SIZE / 8];
+        for idx in 0..page.len() {
+            let at = address + idx * 8;
+            let words = if idx + crossing.len() <= page.len() {
+                &page[idx..idx + crossing.len()]
+            } else if at + HEADER_SIZE <= smap.to {
+                crossing[0] = page[idx];
+                if !unsafe { &*(crossing.as_ptr() as *const AllocHeader) }.has_magic() {
+                    continue;
+                }
+                target.read(at, as_bytes_mut(&mut crossing))?;
+                &crossing[..]
+            } else {
+                // The rest of the header would be outside of the mapping.
+                continue;
+            };
+            let ah = unsafe { &*(words.as_ptr() as *const AllocHeader) };
+            self.legacy += usize::from(ah.is_legacy_allocated() || ah.is_legacy_freed());
+            if ah.is_allocated() || ah.is_legacy_allocated() {
+                match self.check.implausible_field(ah, at, smap.to) {
+                    Some(field) => self.invalid.push(InvalidHeader { address: at, field }),
+                    None => self.live.push((at, ah.size())),
+                }
+            } else if ah.is_freed() || ah.is_legacy_freed() {
+                self.freed.insert(at + HEADER_SIZE, at);
+            } else if ah.has_magic() && !ah.has_freed_magic() {
+                self.invalid.push(InvalidHeader { address: at, field: "checksum" });
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds overlapping live allocations and forgets freed headers inside of live allocations,
+    /// which are stale data.
+    fn find_overlaps(&mut self) {
+        self.live.sort_unstable();
+        let mut outer: Option<(usize, usize)> = None;
+        for &(header, size) in &self.live {
+            match outer {
+                Some((start, end)) if header < end => self.overlaps.push((start, header)),
+                _ => outer = Some((header, header + HEADER_SIZE + size)),
+            }
+        }
+        let live = &self.live;
+        self.freed.retain(|_, header| Self::owner(live, *header).is_none());
+    }
+
+    /// Header of the live allocation, whose user region contains `address`.
+    fn owner(live: &[(usize, usize)], address: usize) -> Option<usize> {
+        let idx = live.partition_point(|&(header, _)| header <= address).checked_sub(1)?;
+        let (header, size) = live[idx];
+        (header + HEADER_SIZE <= address && address < header + HEADER_SIZE + size).then_some(header)
+    }
+
+    /// Records pointers to freed allocations in live allocations and on thread stacks on the page
+    /// at `address`. Pointers in other memory, e.g. in freed allocations, are stale.
+    fn scan_pointers(&mut self, smap: &Smap, address: usize, page: &[usize]) {
+        for (idx, &word) in page.iter().enumerate() {
+            if let Some(&freed) = self.freed.get(&word) {
+                let at = address + idx * 8;
+                let owner = Self::owner(&self.live, at);
+                if owner.is_some() || smap.is_stack {
+                    self.dangling.push(DanglingPointer { address: at, freed, owner });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::analyze::Target;
+    use crate::integrity::{DanglingPointer, Integrity, IntegrityCmd, InvalidHeader, HEADER_SIZE};
+    use crate::utils::parse_smaps;
+    use near_rust_allocator_proxy::{AllocHeader, ProxyAllocator};
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::fs::File;
+
+    #[test]
+    fn test_integrity() {
+        const FROM: usize = 0x10000;
+        let line = format!("{:x}-{:x} rw-p 00000000 00:00 0", FROM, FROM + 4096);
+        let smaps = parse_smaps([line].into_iter()).unwrap();
+
+        let alloc = ProxyAllocator::new(System);
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let ptr = unsafe { alloc.alloc(layout) };
+        let header: [usize; 4] = unsafe { ptr.sub(HEADER_SIZE).cast::<[usize; 4]>().read() };
+        unsafe { alloc.dealloc(ptr, layout) };
+        let mut freed = header;
+        unsafe { (*(freed.as_mut_ptr() as *mut AllocHeader)).mark_as_freed() };
+
+        let mut page = vec![0usize; 512];
+        // Live allocations at words 0 and 100, a header inside of the first one overlaps it.
+        page[0..4].copy_from_slice(&header);
+        page[8..12].copy_from_slice(&header);
+        page[100..104].copy_from_slice(&header);
+        // A freed allocation referenced from the one at word 100 and from free memory.
+        page[200..204].copy_from_slice(&freed);
+        page[110] = FROM + 200 * 8 + HEADER_SIZE;
+        page[300] = FROM + 200 * 8 + HEADER_SIZE;
+        // A header with a corrupted size.
+        page[400..404].copy_from_slice(&header);
+        page[401] += 1;
+        // Freed blocks can be reused by the allocator, so the same isn't a problem for them.
+        page[450..454].copy_from_slice(&freed);
+        page[451] += 1;
+
+        let mut integrity = Integrity::new(&smaps);
+        // Only read for headers crossing the end of the page, there are none.
+        let pagemap = File::open("/proc/self/pagemap").unwrap();
+        let target = Target::Process { pid: std::process::id() as i32, pagemap };
+        integrity.scan_headers(&target, &smaps[0], FROM, &page).unwrap();
+        integrity.find_overlaps();
+        integrity.scan_pointers(&smaps[0], FROM, &page);
+
+        assert_eq!(
+            integrity.invalid,
+            vec![InvalidHeader { address: FROM + 400 * 8, field: "checksum" }]
+        );
+        assert_eq!(integrity.overlaps, vec![(FROM, FROM + 8 * 8)]);
+        assert_eq!(
+            integrity.dangling,
+            vec![DanglingPointer {
+                address: FROM + 110 * 8,
+                freed: FROM + 200 * 8,
+                owner: Some(FROM + 100 * 8)
+            }]
+        );
+    }
+
+    #[test]
+    fn test_header_crossing_pages() {
+        let alloc = ProxyAllocator::new(System);
+        let layout = Layout::from_size_align(16, 8).unwrap();
+        let ptr = unsafe { alloc.alloc(layout) };
+        let header: [usize; 4] = unsafe { ptr.sub(HEADER_SIZE).cast::<[usize; 4]>().read() };
+        unsafe { alloc.dealloc(ptr, layout) };
+
+        // Two "pages" of this process, a header starts in the last 2 words of the first one.
+        let mut memory = vec![0usize; 1024];
+        memory[510..514].copy_from_slice(&header);
+        let from = memory.as_ptr() as usize;
+        let line = format!("{:x}-{:x} rw-p 00000000 00:00 0", from, from + 8192);
+        let smaps = parse_smaps([line].into_iter()).unwrap();
+
+        let mut integrity = Integrity::new(&smaps);
+        let pagemap = File::open("/proc/self/pagemap").unwrap();
+        let target = Target::Process { pid: std::process::id() as i32, pagemap };
+        integrity.scan_headers(&target, &smaps[0], from, &memory[..512]).unwrap();
+        assert_eq!(integrity.live, vec![(from + 510 * 8, 16)]);
+        assert!(integrity.invalid.is_empty());
+    }
+
+    #[test]
+    fn test_freeze_timeout_requires_consistent() {
+        use clap::Parser;
+        let parse = |args: &[&str]| {
+            IntegrityCmd::try_parse_from(["integrity", "--pid", "1"].iter().chain(args))
+        };
+        assert!(parse(&["--freeze-timeout", "5"]).is_err());
+        let cmd = parse(&["--consistent", "--freeze-timeout", "5"]).unwrap();
+        assert_eq!(cmd.freeze_timeout, Some(5));
+        assert_eq!(parse(&["--consistent"]).unwrap().freeze_timeout, None);
+    }
+
+    #[test]
+    fn test_skip_thread_stacks() {
+        const FROM: usize = 0x10000;
+        let line = format!("{:x}-{:x} rw-p 00000000 00:00 0", FROM, FROM + 4096);
+        let mut smaps = parse_smaps([line].into_iter()).unwrap();
+        smaps[0].is_stack = true;
+
+        let alloc = ProxyAllocator::new(System);
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let ptr = unsafe { alloc.alloc(layout) };
+        let header: [usize; 4] = unsafe { ptr.sub(HEADER_SIZE).cast::<[usize; 4]>().read() };
+        unsafe { alloc.dealloc(ptr, layout) };
+
+        // A header being built on the stack, its checksum isn't set yet.
+        let mut page = vec![0usize; 512];
+        page[0..4].copy_from_slice(&header);
+        page[1] += 1;
+        page[20..24].copy_from_slice(&header);
+
+        let mut integrity = Integrity::new(&smaps);
+        let pagemap = File::open("/proc/self/pagemap").unwrap();
+        let target = Target::Process { pid: std::process::id() as i32, pagemap };
+        integrity.scan_headers(&target, &smaps[0], FROM, &page).unwrap();
+        assert!(integrity.invalid.is_empty() && integrity.live.is_empty());
+    }
+}
@@ -1,5 +1,10 @@
 mod analyze;
+mod core_dump;
+mod freeze;
+mod heap_dump;
+mod integrity;
 mod mem_used;
+mod mmap_sites;
 mod opts;
 mod symbols;
 mod utils;
@@ -25,6 +30,8 @@ fn main() -> anyhow::Result<()> {
         SubCommand::Analyze(cmd) => cmd.handle().with_context(|| "analyze_cmd failed")?,
         SubCommand::MemUsed(cmd) => cmd.handle().with_context(|| "query_cmd failed")?,
         SubCommand::Symbols(cmd) => cmd.handle().with_context(|| "symbols_cmd failed")?,
+        SubCommand::Dump(cmd) => cmd.handle().with_context(|| "dump_cmd failed")?,
+        SubCommand::Integrity(cmd) => cmd.handle().with_context(|| "integrity_cmd failed")?,
     };
     Ok(())
 }
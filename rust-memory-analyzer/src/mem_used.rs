@@ -1,37 +1,107 @@
-use crate::utils;
+use crate::heap_dump::HeapDump;
+use crate::utils::{self, MIB};
 use anyhow::Context;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::path::PathBuf;
 use std::time::Instant;
-use tracing::info;
+use tracing::{info, warn};
 
 #[derive(clap_derive::Parser, Debug)]
 pub(crate) struct MemUsedCmd {
+    #[clap(long, required_unless_present("dump"))]
+    pid: Option<i32>,
+    /// Report memory of a heap dump written by `dump` instead of a running process.
+    #[clap(long, conflicts_with("pid"))]
+    dump: Option<PathBuf>,
+    /// Only use counters from smaps instead of counting present pages in pagemap.
     #[clap(long)]
-    pid: i32,
+    fast: bool,
 }
 
 impl MemUsedCmd {
     pub(crate) fn handle(&self) -> anyhow::Result<()> {
-        info!(?self.pid);
-        let smaps = utils::read_smaps(self.pid).with_context(|| "read_smaps failed")?;
+        info!(?self.pid, ?self.dump);
+        let dump = self.dump.as_deref().map(HeapDump::open).transpose()?;
+        let smaps = match (&dump, self.pid) {
+            (Some(dump), _) => dump.smaps(),
+            (None, Some(pid)) => utils::read_smaps(pid).with_context(|| "read_smaps failed")?,
+            (None, None) => anyhow::bail!("either --pid or --dump is required"),
+        };
 
-        let mut total_present_pages = 0;
         let start = Instant::now();
-        let page_map_file = PathBuf::from("/proc").join(self.pid.to_string()).join("pagemap");
-        let mut file = File::open(page_map_file.clone())
-            .with_context(|| format!("page_map_file not found file={:?}", page_map_file))?;
+        // Same anonymous mappings as counted in pagemap below and scanned by `analyze`, including
+        // `[heap]` and stacks.
+        let smaps_rss: usize =
+            smaps.iter().filter(|smap| smap.is_anonymous()).map(|smap| smap.rss()).sum();
+        let swap: usize = smaps.iter().map(|smap| smap.swap()).sum();
+        let pss: usize = smaps.iter().map(|smap| smap.pss()).sum();
+        let anonymous: usize = smaps.iter().map(|smap| smap.anonymous()).sum();
+        info!(
+            mem_used_mb = smaps_rss / MIB,
+            anonymous_mb = anonymous / MIB,
+            pss_mb = pss / MIB,
+            swap_mb = swap / MIB,
+            took = ?start.elapsed(),
+            "smaps"
+        );
+        if self.fast {
+            return Ok(());
+        }
 
-        let page_size = utils::get_page_size()?;
+        let (mut file, page_size) = match (&dump, self.pid) {
+            (Some(dump), _) => (None, dump.page_size),
+            (None, Some(pid)) => {
+                let page_map_file = PathBuf::from("/proc").join(pid.to_string()).join("pagemap");
+                let file = File::open(page_map_file.clone())
+                    .with_context(|| format!("page_map_file not found file={:?}", page_map_file))?;
+                (Some(file), utils::get_page_size()?)
+            }
+            (None, None) => unreachable!(),
+        };
 
-        for (smap, addresses) in
-            utils::compute_present_pages(&smaps, &mut file, page_size, false)?.iter()
-        {
-            info!(?smap, len = addresses.len());
-            total_present_pages += addresses.len();
+        let mut total_present_pages = 0;
+        let (mut swapped, mut shared, mut exclusive, mut soft_dirty) = (0, 0, 0, 0);
+        let mut swapped_per_type: BTreeMap<u64, usize> = BTreeMap::new();
+        for smap in smaps.iter().filter(|smap| smap.is_anonymous()) {
+            let entries = match (&dump, &mut file) {
+                (Some(dump), _) => dump.page_map(smap),
+                (None, Some(file)) => utils::read_page_map(smap, file, page_size)?,
+                (None, None) => unreachable!(),
+            };
+            let present = entries.iter().filter(|(_, entry)| entry.present()).count();
+            info!(?smap, len = present);
+            total_present_pages += present;
+            for (_, entry) in entries {
+                shared += usize::from(entry.present() && entry.file_or_shared_anon());
+                exclusive += usize::from(entry.present() && entry.exclusive());
+                soft_dirty += usize::from(entry.soft_dirty());
+                if let Some(swap_type) = entry.swap_type() {
+                    swapped += 1;
+                    *swapped_per_type.entry(swap_type).or_default() += page_size;
+                }
+            }
         }
         // compute memory used in not mmaped files
-        info!(mem_used_mb = total_present_pages * page_size / crate::utils::MIB, total_present_pages, took = ?start.elapsed());
+        info!(mem_used_mb = total_present_pages * page_size / MIB, total_present_pages, took = ?start.elapsed());
+        info!(
+            swapped_mb = swapped * page_size / MIB,
+            shared_mb = shared * page_size / MIB,
+            exclusive_mb = exclusive * page_size / MIB,
+            soft_dirty_mb = soft_dirty * page_size / MIB,
+            ?swapped_per_type,
+            "pagemap"
+        );
+
+        // Both numbers are taken at slightly different times, so allow for some difference.
+        let pagemap_rss = total_present_pages * page_size;
+        if pagemap_rss.abs_diff(smaps_rss) > pagemap_rss.max(smaps_rss) / 100 {
+            warn!(
+                pagemap_mb = pagemap_rss / MIB,
+                smaps_mb = smaps_rss / MIB,
+                "memory used according to pagemap and smaps differs by more than 1%"
+            );
+        }
         Ok(())
     }
 }
@@ -0,0 +1,137 @@
+//! Attribution of anonymous mappings to the code, which created them, using the tables of live
+//! mappings in the mmap logs written by `near-rust-allocator-proxy`.
+use crate::analyze::Target;
+use crate::utils::{Counter, Smap};
+use near_rust_allocator_proxy::{
+    mmap_log_size, parse_live_mappings, parse_mmap_log, LiveMapping, MMAP_LOG_HEADER_SIZE,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::c_void;
+use tracing::{info, warn};
+
+/// Live mappings in the tables of all mmap logs found at the start of anonymous mappings, oldest
+/// first, and the number of mappings, which didn't fit into the tables.
+///
+/// A process can have more than one log, e.g. a Rust program using `near-rust-allocator-proxy`
+/// run with `near-c-allocator-proxy` preloaded.
+pub(crate) fn read_mmap_logs(target: &Target, smaps: &[Smap]) -> (Vec<LiveMapping>, usize) {
+    let (mut mappings, mut dropped) = (Vec::new(), 0);
+    for smap in smaps.iter().filter(|smap| smap.is_anonymous() && smap.perms.starts_with("rw")) {
+        let mut header = [0; MMAP_LOG_HEADER_SIZE];
+        if smap.to - smap.from < header.len() || target.read(smap.from, &mut header).is_err() {
+            continue;
+        }
+        let size = match mmap_log_size(&header) {
+            Some(size) if size <= smap.to - smap.from => size,
+            _ => continue,
+        };
+        // Read twice to skip entries being written meanwhile, unless the process is stopped.
+        let (mut log, mut again) = (vec![0; size], vec![0; size]);
+        if let Err(err) =
+            (target.read(smap.from, &mut log)).and_then(|()| target.read(smap.from, &mut again))
+        {
+            warn!(?err, address = smap.from, "Failed to read mmap log.");
+            continue;
+        }
+        let (events, overwritten) = parse_mmap_log(&log, &again);
+        let (found, found_dropped) = parse_live_mappings(&log, &again);
+        info!(
+            address = smap.from,
+            events = events.len(),
+            overwritten,
+            mappings = found.len(),
+            dropped = found_dropped,
+            "Found mmap log."
+        );
+        mappings.extend(found);
+        dropped += found_dropped;
+    }
+    // Stable, mappings made at the same time stay in the order of their table.
+    mappings.sort_by_key(|mapping| mapping.time);
+    (mappings, dropped)
+}
+
+/// Live mappings of all tables as `start -> (end, site)`, parts of mappings made later replace
+/// overlapping parts of older ones.
+pub(crate) fn live_mappings(found: &[LiveMapping]) -> BTreeMap<usize, (usize, *mut c_void)> {
+    let mut mappings = BTreeMap::new();
+    for mapping in found {
+        unmap(&mut mappings, mapping.start, mapping.end);
+        mappings.insert(mapping.start, (mapping.end, mapping.site));
+    }
+    mappings
+}
+
+/// Removes `[start, end)` from `mappings`, keeping the parts of mappings outside of it.
+fn unmap(mappings: &mut BTreeMap<usize, (usize, *mut c_void)>, start: usize, end: usize) {
+    // Mappings don't overlap, so their ends are sorted too.
+    let overlapping: Vec<_> = (mappings.range(..end).rev())
+        .take_while(|(_, (mapping_end, _))| *mapping_end > start)
+        .map(|(&mapping_start, &(mapping_end, site))| (mapping_start, mapping_end, site))
+        .collect();
+    for (mapping_start, mapping_end, site) in overlapping {
+        mappings.remove(&mapping_start);
+        if mapping_start < start {
+            mappings.insert(mapping_start, (start, site));
+        }
+        if mapping_end > end {
+            mappings.insert(end, (mapping_end, site));
+        }
+    }
+}
+
+/// Number of mappings with resident pages and resident bytes per site, for resident `pages`.
+pub(crate) fn resident_by_site(
+    mappings: &BTreeMap<usize, (usize, *mut c_void)>,
+    pages: impl Iterator<Item = usize>,
+    page_size: usize,
+) -> HashMap<*mut c_void, Counter> {
+    let mut resident: HashMap<usize, usize> = HashMap::new();
+    for page in pages {
+        if let Some((&start, &(end, _))) = mappings.range(..=page).next_back() {
+            if page < end {
+                *resident.entry(start).or_default() += page_size;
+            }
+        }
+    }
+    let mut by_site: HashMap<*mut c_void, Counter> = HashMap::new();
+    for (start, size) in resident {
+        *by_site.entry(mappings[&start].1).or_default() += Counter { cnt: 1, size };
+    }
+    by_site
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mmap_sites::{live_mappings, resident_by_site};
+    use near_rust_allocator_proxy::LiveMapping;
+    use std::collections::BTreeMap;
+    use std::ffi::c_void;
+
+    #[test]
+    fn test_live_mappings() {
+        let (first, second) = (0x100 as *mut c_void, 0x200 as *mut c_void);
+        let mapping = |start, end, site, time| LiveMapping { start, end, site, tid: 1, time };
+        let found = [
+            mapping(0x10000, 0x14000, first, 0),
+            mapping(0x16000, 0x20000, first, 0),
+            // Tables of two logs overlap, if one missed an unmap.
+            mapping(0x18000, 0x19000, second, 1),
+        ];
+        let mappings = live_mappings(&found);
+        assert_eq!(
+            mappings,
+            BTreeMap::from([
+                (0x10000, (0x14000, first)),
+                (0x16000, (0x18000, first)),
+                (0x18000, (0x19000, second)),
+                (0x19000, (0x20000, first)),
+            ])
+        );
+
+        let pages = [0x0, 0x10000, 0x17000, 0x18000, 0x1f000, 0x20000, 0x30000];
+        let by_site = resident_by_site(&mappings, pages.into_iter(), 0x1000);
+        assert_eq!((by_site[&first].cnt, by_site[&first].size), (3, 0x3000));
+        assert_eq!((by_site[&second].cnt, by_site[&second].size), (1, 0x1000));
+    }
+}
@@ -1,4 +1,6 @@
 use crate::analyze::AnalyzeCmd;
+use crate::heap_dump::DumpCmd;
+use crate::integrity::IntegrityCmd;
 use crate::mem_used::MemUsedCmd;
 use crate::symbols::SymbolsCmd;
 use clap::AppSettings;
@@ -16,4 +18,6 @@ pub(super) enum SubCommand {
     MemUsed(MemUsedCmd),
     Analyze(AnalyzeCmd),
     Symbols(SymbolsCmd),
+    Dump(DumpCmd),
+    Integrity(IntegrityCmd),
 }
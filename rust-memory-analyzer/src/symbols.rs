@@ -27,6 +27,12 @@ impl SymbolsCmd {
     }
 }
 
+/// Finds the symbol containing `offset`, `symbols` have to be sorted by offset.
+pub fn find_symbol(symbols: &[Symbol], offset: usize) -> Option<&Symbol> {
+    let idx = symbols.partition_point(|s| s.offset <= offset);
+    idx.checked_sub(1).map(|idx| &symbols[idx])
+}
+
 pub fn get_symbols(binary_path: &str) -> anyhow::Result<Vec<Symbol>> {
     let output = (Command::new("nm").arg("-an").arg(binary_path))
         .stdout(Stdio::piped())
@@ -44,3 +50,25 @@ pub fn get_symbols(binary_path: &str) -> anyhow::Result<Vec<Symbol>> {
         })
         .collect())
 }
+
+#[cfg(test)]
+mod test {
+    use crate::symbols::{find_symbol, Symbol};
+
+    #[test]
+    fn test_find_symbol() {
+        let symbols: Vec<_> = [0x10, 0x20, 0x30]
+            .iter()
+            .map(|&offset| Symbol {
+                offset,
+                unk: "T".to_string(),
+                raw_symbol: format!("f{:x}", offset),
+                symbol: format!("f{:x}", offset),
+            })
+            .collect();
+        assert!(find_symbol(&symbols, 0x0f).is_none());
+        assert_eq!(find_symbol(&symbols, 0x10).unwrap().offset, 0x10);
+        assert_eq!(find_symbol(&symbols, 0x2f).unwrap().offset, 0x20);
+        assert_eq!(find_symbol(&symbols, 0x100).unwrap().offset, 0x30);
+    }
+}
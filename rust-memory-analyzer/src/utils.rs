@@ -1,10 +1,15 @@
+use crate::freeze::Freeze;
 use anyhow::Context;
+use object::Object;
+use std::collections::BTreeMap;
+use std::fs;
 use std::fs::File;
 use std::io::{BufRead, Read, Seek, SeekFrom};
 use std::ops::AddAssign;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use std::{io, usize};
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 pub const MIB: usize = 1 << 20;
 
@@ -16,23 +21,134 @@ pub fn read_lines(file_name: impl AsRef<Path>) -> anyhow::Result<impl Iterator<I
 pub fn read_smaps(pid: i32) -> anyhow::Result<Vec<Smap>> {
     let path = PathBuf::from("/proc").join(pid.to_string()).join("smaps");
     info!(?path);
-    Ok(read_lines(path.clone())
-        .with_context(|| format!("cant open path={:?}", &path))?
-        .map(|l| (l.split(' ').map(|s| s.to_string()).collect::<Vec<_>>(), l))
-        .filter(|(s, _)| s.len() >= 3 && s[0].contains('-'))
-        .map(|(sp, line)| {
-            let (addresses, _flags, offset) = (&sp[0], &sp[1], &sp[2]);
-
-            let split = addresses.split_once('-').unwrap();
-            Smap {
-                from: usize::from_str_radix(split.0, 16).unwrap_or_default(),
-                to: usize::from_str_radix(split.1, 16).unwrap_or_default(),
-                mapped_file: if line.len() > 73 { Some(line[73..].to_string()) } else { None },
-                is_stack: false,
-                offset: usize::from_str_radix(offset, 16).unwrap_or_default(),
-            }
-        })
-        .collect())
+    let mut smaps = parse_smaps(
+        read_lines(path.clone()).with_context(|| format!("cant open path={:?}", &path))?,
+    )
+    .with_context(|| format!("failed to parse {:?}", &path))?;
+
+    mark_stacks(&mut smaps, &thread_stack_pointers(pid));
+    Ok(smaps)
+}
+
+/// Sets `is_stack` of the main thread stack and of anonymous mappings, which contain one of
+/// `stack_pointers`. Other anonymous mappings can't be told apart from thread stacks reliably,
+/// e.g. guard pages are also used by wasm memories and the mmap log.
+pub fn mark_stacks(smaps: &mut [Smap], stack_pointers: &[usize]) {
+    for smap in smaps {
+        smap.is_stack = if let Some(file) = &smap.mapped_file {
+            file.starts_with("[stack")
+        } else {
+            stack_pointers.iter().any(|&sp| smap.from <= sp && sp < smap.to)
+        };
+    }
+}
+
+/// Parses the contents of `/proc/<pid>/smaps`.
+pub fn parse_smaps(lines: impl Iterator<Item = String>) -> anyhow::Result<Vec<Smap>> {
+    let mut res: Vec<Smap> = Vec::new();
+    for line in lines {
+        if let Some(flags) = line.strip_prefix("VmFlags:") {
+            let smap = res.last_mut().with_context(|| "VmFlags before the first mapping")?;
+            smap.vm_flags = flags.split_whitespace().map(str::to_string).collect();
+            continue;
+        }
+        let (first, rest) = line.split_once(char::is_whitespace).unwrap_or((&line, ""));
+        if let Some(name) = first.strip_suffix(':') {
+            let smap =
+                res.last_mut().with_context(|| format!("{} before the first mapping", name))?;
+            let mut value = rest.split_whitespace();
+            let number: usize = value.next().unwrap_or_default().parse().unwrap_or_default();
+            let number = if value.next() == Some("kB") { number * 1024 } else { number };
+            smap.fields.insert(name.to_string(), number);
+            continue;
+        }
+
+        // Header line: `<from>-<to> <perms> <offset> <dev> <inode> [<path>]`, the path can
+        // contain spaces and is padded to a column, which depends on the width of addresses.
+        let (from, to) = first.split_once('-').with_context(|| format!("bad line {:?}", line))?;
+        let mut rest = rest;
+        let mut next = || {
+            let (field, tail) =
+                rest.trim_start().split_once(' ').unwrap_or((rest.trim_start(), ""));
+            rest = tail;
+            field
+        };
+        let (perms, offset, dev, inode) = (next(), next(), next(), next());
+        let path = rest.trim_start();
+        res.push(Smap {
+            from: usize::from_str_radix(from, 16)?,
+            to: usize::from_str_radix(to, 16)?,
+            perms: perms.to_string(),
+            offset: usize::from_str_radix(offset, 16)?,
+            dev: dev.to_string(),
+            inode: inode.parse()?,
+            mapped_file: if path.is_empty() { None } else { Some(path.to_string()) },
+            is_stack: false,
+            fields: BTreeMap::new(),
+            vm_flags: Vec::new(),
+        });
+    }
+    Ok(res)
+}
+
+/// Stack pointers of threads of `pid`, see `read_stack_pointers`. Threads running on a CPU have
+/// none, if there are any, the process is stopped briefly to read them again.
+pub fn thread_stack_pointers(pid: i32) -> Vec<usize> {
+    let (mut res, mut missing) = read_stack_pointers(pid);
+    if missing != 0 {
+        match Freeze::new(pid, Duration::from_secs(1)) {
+            Ok(_freeze) => (res, missing) = read_stack_pointers(pid),
+            Err(err) => warn!(?err, "Failed to stop the process to read its stack pointers."),
+        }
+    }
+    if missing != 0 {
+        warn!(threads = missing, "Stacks of threads without a stack pointer aren't detected.");
+    }
+    res
+}
+
+/// Stack pointers of threads of `pid` from `/proc/<pid>/task/<tid>/syscall`, or `kstkesp` in
+/// `/proc/<pid>/task/<tid>/stat` for running threads, and the number of threads with neither. The
+/// latter is only set by some kernels.
+fn read_stack_pointers(pid: i32) -> (Vec<usize>, usize) {
+    let task = PathBuf::from("/proc").join(pid.to_string()).join("task");
+    let (mut res, mut missing) = (Vec::new(), 0);
+    for entry in fs::read_dir(task).into_iter().flatten().flatten() {
+        let syscall = fs::read_to_string(entry.path().join("syscall")).unwrap_or_default();
+        let stat = fs::read_to_string(entry.path().join("stat")).unwrap_or_default();
+        match parse_syscall_sp(&syscall).or_else(|| parse_stat_sp(&stat)) {
+            Some(sp) => res.push(sp),
+            None => missing += 1,
+        }
+    }
+    (res, missing)
+}
+
+/// Stack pointer in `/proc/<pid>/task/<tid>/syscall`: `<nr> <args>... <sp> <pc>`, or just
+/// `running`.
+fn parse_syscall_sp(syscall: &str) -> Option<usize> {
+    let fields: Vec<_> = syscall.split_whitespace().collect();
+    if fields.len() < 3 {
+        return None;
+    }
+    usize::from_str_radix(fields[fields.len() - 2].trim_start_matches("0x"), 16).ok()
+}
+
+/// `kstkesp`, the 29th field of `/proc/<pid>/task/<tid>/stat`, if not zero. The command name in
+/// the 2nd field can contain spaces and parentheses.
+fn parse_stat_sp(stat: &str) -> Option<usize> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    // `fields` start with the 3rd field.
+    let sp: usize = fields.split_whitespace().nth(29 - 3)?.parse().ok()?;
+    (sp != 0).then_some(sp)
+}
+
+/// GNU build id of the ELF file at `path` as a hex string.
+pub fn read_build_id(path: impl AsRef<Path>) -> Option<String> {
+    let data = fs::read(path).ok()?;
+    let file = object::File::parse(data.as_slice()).ok()?;
+    let build_id = file.build_id().ok()??;
+    Some(build_id.iter().map(|b| format!("{:02x}", b)).collect())
 }
 
 /// 4096 on `x86_64` linux
@@ -63,42 +179,341 @@ impl AddAssign for Counter {
     }
 }
 
+/// Number and size of allocations per power-of-two size bucket, bucket `i` holds allocations of
+/// size in `(2^(i-1), 2^i]`.
+#[derive(Debug, Default, Clone)]
+pub struct SizeHistogram {
+    pub buckets: BTreeMap<u32, Counter>,
+}
+
+impl SizeHistogram {
+    pub fn add(&mut self, size: usize) {
+        let bucket = usize::BITS - size.saturating_sub(1).leading_zeros();
+        *self.buckets.entry(bucket).or_default() += Counter::with_size(size);
+    }
+
+    /// Upper bound of sizes in `bucket`.
+    pub fn upper_bound(bucket: u32) -> u64 {
+        1_u64.checked_shl(bucket).unwrap_or(u64::MAX)
+    }
+}
+
+impl AddAssign<&SizeHistogram> for SizeHistogram {
+    fn add_assign(&mut self, other: &Self) {
+        for (bucket, counter) in &other.buckets {
+            *self.buckets.entry(*bucket).or_default() += *counter;
+        }
+    }
+}
+
+/// Memory mapping of a process as described by `/proc/<pid>/smaps`.
 #[derive(Debug, Clone)]
 pub struct Smap {
     pub from: usize,
     pub to: usize,
-    pub mapped_file: Option<String>,
-    #[allow(unused)]
-    is_stack: bool,
+    /// Permissions, e.g. `rw-p`.
+    pub perms: String,
     pub offset: usize,
+    /// Device as `major:minor`.
+    pub dev: String,
+    pub inode: u64,
+    /// Path of the mapped file or a pseudo path like `[heap]`.
+    pub mapped_file: Option<String>,
+    /// Main thread stack or the stack of another thread.
+    pub is_stack: bool,
+    /// Fields like `Rss` or `Swap`, sizes are converted to bytes.
+    pub fields: BTreeMap<String, usize>,
+    /// Two letter flags from `VmFlags`, e.g. `rd`.
+    pub vm_flags: Vec<String>,
 }
 
-pub fn compute_present_pages(
+impl Smap {
+    /// Value of the field `name`, `0` if missing.
+    pub fn field(&self, name: &str) -> usize {
+        self.fields.get(name).copied().unwrap_or_default()
+    }
+
+    pub fn rss(&self) -> usize {
+        self.field("Rss")
+    }
+
+    pub fn pss(&self) -> usize {
+        self.field("Pss")
+    }
+
+    pub fn anonymous(&self) -> usize {
+        self.field("Anonymous")
+    }
+
+    pub fn swap(&self) -> usize {
+        self.field("Swap")
+    }
+
+    /// Whether the mapping is anonymous memory, including `[heap]` used by `brk` based allocators
+    /// like the one of glibc, stacks and named anonymous mappings.
+    pub fn is_anonymous(&self) -> bool {
+        self.mapped_file.as_deref().map_or(true, |file| {
+            file == "[heap]" || file.starts_with("[stack") || file.starts_with("[anon:")
+        })
+    }
+}
+
+/// Addresses of pages of `smaps`, which are file backed if `mapped` is set and anonymous
+/// otherwise, with pagemap entries matching `filter`. Entries are read with `page_map`, e.g.
+/// [`read_page_map`].
+pub fn compute_pages(
     smaps: &[Smap],
-    file: &mut File,
-    page_size: usize,
     mapped: bool,
+    filter: impl Fn(PageMapEntry) -> bool,
+    mut page_map: impl FnMut(&Smap) -> anyhow::Result<Vec<(usize, PageMapEntry)>>,
 ) -> anyhow::Result<Vec<(Smap, Vec<usize>)>> {
     let mut res = Vec::new();
-    for smap in smaps.iter().filter(|s| s.mapped_file.is_some() == mapped) {
-        // Verify that all memory regions are divided into pages.
-        file.seek(SeekFrom::Start((smap.from / page_size * PAGE_MAP_ENTRY_SIZE) as u64))?;
-        let entries = (smap.to - smap.from) / page_size;
-        let mut x: Vec<u8> = Vec::new();
-        x.resize(entries * PAGE_MAP_ENTRY_SIZE, 0);
-
-        let read = file.read(x.as_mut_slice()).with_context(|| "read_exact")?;
-        if read != entries {
-            debug!(read, entries, "didn't ready the buffer completely");
-        }
-        let present_count: Vec<usize> = (0..entries)
-            .filter(|i| x[8 * i + 7] & (1 << 7) != 0)
-            .map(|i| smap.from + i * page_size)
+    for smap in smaps.iter().filter(|s| s.is_anonymous() != mapped) {
+        let pages = page_map(smap)?
+            .into_iter()
+            .filter(|(_, entry)| filter(*entry))
+            .map(|(address, _)| address)
             .collect();
-        res.push((smap.clone(), present_count));
+        res.push((smap.clone(), pages));
     }
     Ok(res)
 }
 
+/// Address and pagemap entry of each page of `smap`.
+pub fn read_page_map(
+    smap: &Smap,
+    file: &mut File,
+    page_size: usize,
+) -> anyhow::Result<Vec<(usize, PageMapEntry)>> {
+    // Verify that all memory regions are divided into pages.
+    file.seek(SeekFrom::Start((smap.from / page_size * PAGE_MAP_ENTRY_SIZE) as u64))?;
+    let entries = (smap.to - smap.from) / page_size;
+    let mut x: Vec<u8> = Vec::new();
+    x.resize(entries * PAGE_MAP_ENTRY_SIZE, 0);
+
+    let read = file.read(x.as_mut_slice()).with_context(|| "read_exact")?;
+    if read != x.len() {
+        debug!(read, entries, "didn't ready the buffer completely");
+    }
+    Ok(x.chunks_exact(PAGE_MAP_ENTRY_SIZE)
+        .enumerate()
+        .map(|(i, entry)| {
+            (smap.from + i * page_size, PageMapEntry(u64::from_le_bytes(entry.try_into().unwrap())))
+        })
+        .collect())
+}
+
+/// Entry of `/proc/<pid>/pagemap` describing a single page.
+///
+/// See <https://www.kernel.org/doc/Documentation/vm/pagemap.txt>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageMapEntry(pub u64);
+
+impl PageMapEntry {
+    pub fn present(self) -> bool {
+        self.0 & (1 << 63) != 0
+    }
+
+    pub fn swapped(self) -> bool {
+        self.0 & (1 << 62) != 0
+    }
+
+    /// Page is file backed or shared anonymous memory.
+    pub fn file_or_shared_anon(self) -> bool {
+        self.0 & (1 << 61) != 0
+    }
+
+    /// Page is mapped only by this process.
+    pub fn exclusive(self) -> bool {
+        self.0 & (1 << 56) != 0
+    }
+
+    /// Page was written to since soft-dirty bits were last cleared.
+    pub fn soft_dirty(self) -> bool {
+        self.0 & (1 << 55) != 0
+    }
+
+    pub fn swap_type(self) -> Option<u64> {
+        self.swapped().then_some(self.0 & 0x1f)
+    }
+}
+
+/// Estimated size of the jemalloc size class `size` is rounded up to. There are 4 size classes
+/// for each doubling of size, with a minimum spacing of 16 bytes.
+pub fn jemalloc_size_class(size: usize) -> usize {
+    if size <= 8 {
+        return 8;
+    }
+    if size <= 16 {
+        return 16;
+    }
+    let lg_floor = usize::BITS - 1 - (size - 1).leading_zeros();
+    let spacing = (1_usize << lg_floor.saturating_sub(2)).max(16);
+    (size + spacing - 1) / spacing * spacing
+}
+
+/// Bytes of each page covered by allocations.
+///
+/// `pages` are sorted page addresses, `extents` sorted `(start, len)` of non-overlapping
+/// allocations.
+pub fn page_usage(pages: &[usize], extents: &[(usize, usize)], page_size: usize) -> Vec<usize> {
+    let mut res = Vec::with_capacity(pages.len());
+    // Extents before this one end before the current page.
+    let mut first = 0;
+    for &page in pages {
+        let page_end = page + page_size;
+        while first < extents.len() && extents[first].0 + extents[first].1 <= page {
+            first += 1;
+        }
+        let used = extents[first..]
+            .iter()
+            .take_while(|(start, _)| *start < page_end)
+            .map(|&(start, len)| (start + len).min(page_end).saturating_sub(start.max(page)))
+            .sum();
+        res.push(used);
+    }
+    res
+}
+
 // https://www.kernel.org/doc/Documentation/vm/pagemap.txt
 const PAGE_MAP_ENTRY_SIZE: usize = std::mem::size_of::<u64>();
+
+#[cfg(test)]
+mod test {
+    use crate::utils::{
+        jemalloc_size_class, mark_stacks, page_usage, parse_smaps, parse_stat_sp, parse_syscall_sp,
+        read_smaps, read_stack_pointers, thread_stack_pointers, PageMapEntry, SizeHistogram,
+    };
+    use std::process::Command;
+
+    #[test]
+    fn test_size_histogram() {
+        let mut histogram = SizeHistogram::default();
+        for size in [0, 1, 24, 32, 33, 4096] {
+            histogram.add(size);
+        }
+        let buckets: Vec<_> = histogram.buckets.iter().map(|(b, c)| (*b, c.cnt, c.size)).collect();
+        assert_eq!(buckets, vec![(0, 2, 1), (5, 2, 56), (6, 1, 33), (12, 1, 4096)]);
+        assert_eq!(SizeHistogram::upper_bound(5), 32);
+    }
+
+    #[test]
+    fn test_jemalloc_size_class() {
+        let classes: Vec<_> = [1, 9, 17, 33, 64, 65, 129, 257, 4097]
+            .iter()
+            .map(|&size| jemalloc_size_class(size))
+            .collect();
+        assert_eq!(classes, vec![8, 16, 32, 48, 64, 80, 160, 320, 5120]);
+    }
+
+    #[test]
+    fn test_page_usage() {
+        let pages = [0x1000, 0x2000, 0x3000, 0x5000];
+        let extents = [(0x1000, 0x100), (0x1f00, 0x1200), (0x5000, 0x1000)];
+        assert_eq!(page_usage(&pages, &extents, 0x1000), vec![0x200, 0x1000, 0x100, 0x1000]);
+    }
+
+    #[test]
+    fn test_parse_smaps() {
+        let smaps = "\
+00400000-00452000 r-xp 00001000 08:02 173521      /usr/bin/my program (deleted)
+Size:                328 kB
+Rss:                 300 kB
+Swap:                  4 kB
+THPeligible:           0
+VmFlags: rd ex mr mw me dw
+7fff0f3a9000-7fff0f3ca000 rw-p 00000000 00:00 0                          [stack]
+Rss:                  12 kB
+7f0000000000-7f0000001000 ---p 00000000 00:00 0 
+Rss:                   0 kB
+";
+        let smaps = parse_smaps(smaps.lines().map(str::to_string)).unwrap();
+        assert_eq!(smaps.len(), 3);
+        assert_eq!((smaps[0].from, smaps[0].to, smaps[0].offset), (0x400000, 0x452000, 0x1000));
+        assert_eq!((smaps[0].perms.as_str(), smaps[0].dev.as_str()), ("r-xp", "08:02"));
+        assert_eq!(smaps[0].inode, 173521);
+        assert_eq!(smaps[0].mapped_file.as_deref(), Some("/usr/bin/my program (deleted)"));
+        assert_eq!((smaps[0].rss(), smaps[0].swap()), (300 * 1024, 4 * 1024));
+        assert_eq!(smaps[0].field("THPeligible"), 0);
+        assert_eq!(smaps[0].vm_flags, vec!["rd", "ex", "mr", "mw", "me", "dw"]);
+        assert_eq!(smaps[1].mapped_file.as_deref(), Some("[stack]"));
+        assert_eq!(smaps[2].mapped_file, None);
+        assert_eq!(smaps[2].perms, "---p");
+    }
+
+    #[test]
+    fn test_is_anonymous() {
+        let smaps = "\
+00400000-00452000 r-xp 00000000 08:02 173521                             /usr/bin/test
+00652000-00653000 rw-p 00000000 00:00 0 
+01000000-01100000 rw-p 00000000 00:00 0                                  [heap]
+7f0000000000-7f0000001000 rw-p 00000000 00:00 0                          [anon:wasm]
+7fff0f3a9000-7fff0f3ca000 rw-p 00000000 00:00 0                          [stack]
+7fff0f3fc000-7fff0f3fe000 r-xp 00000000 00:00 0                          [vdso]
+";
+        let smaps = parse_smaps(smaps.lines().map(str::to_string)).unwrap();
+        let anonymous: Vec<_> = smaps.iter().map(|smap| smap.is_anonymous()).collect();
+        assert_eq!(anonymous, vec![false, true, true, true, true, false]);
+    }
+
+    #[test]
+    fn test_page_map_entry() {
+        let present = PageMapEntry(1 << 63 | 1 << 56 | 1 << 55 | 0x1234);
+        assert!(present.present() && present.exclusive() && present.soft_dirty());
+        assert!(!present.swapped() && !present.file_or_shared_anon());
+        assert_eq!(present.swap_type(), None);
+
+        let swapped = PageMapEntry(1 << 62 | 0x1234 << 5 | 3);
+        assert!(swapped.swapped() && !swapped.present());
+        assert_eq!(swapped.swap_type(), Some(3));
+    }
+
+    #[test]
+    fn test_mark_stacks() {
+        let smaps = "\
+7f0000000000-7f0000001000 ---p 00000000 00:00 0 
+7f0000001000-7f0000100000 rw-p 00000000 00:00 0 
+7f0000200000-7f0000201000 ---p 00000000 00:00 0 
+7f0000201000-7f0000300000 rw-p 00000000 00:00 0 
+7fff0f3a9000-7fff0f3ca000 rw-p 00000000 00:00 0                          [stack]
+";
+        let mut smaps = parse_smaps(smaps.lines().map(str::to_string)).unwrap();
+        mark_stacks(&mut smaps, &[0x7f00000ff000]);
+        let stacks: Vec<_> = smaps.iter().map(|smap| smap.is_stack).collect();
+        // The mapping after the second guard page isn't a stack of a thread.
+        assert_eq!(stacks, vec![false, true, false, false, true]);
+    }
+
+    #[test]
+    fn test_parse_stack_pointers() {
+        let syscall = "202 0x7f1c 0x80 0x0 0x0 0x0 0x0 0x7ffd4a3b8e60 0x7f1c2e0f4d5e";
+        assert_eq!(parse_syscall_sp(syscall), Some(0x7ffd4a3b8e60));
+        assert_eq!(parse_syscall_sp("running\n"), None);
+        let stat = format!("12 (a) b) R {} 140726853734000 0 0\n", ["0"; 25].join(" "));
+        assert_eq!(parse_stat_sp(&stat), Some(140726853734000));
+        let stat = format!("12 (a) R {} 0 0 0\n", ["0"; 25].join(" "));
+        assert_eq!(parse_stat_sp(&stat), None);
+    }
+
+    #[test]
+    fn test_running_thread_stack() {
+        let mut child = Command::new("sh").args(["-c", "while :; do :; done"]).spawn().unwrap();
+        let pid = child.id() as i32;
+        // Wait until the loop runs, on kernels setting `kstkesp` the thread never lacks one.
+        for _ in 0..1000 {
+            if read_stack_pointers(pid).1 != 0 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        let stack_pointers = thread_stack_pointers(pid);
+        let smaps = read_smaps(pid).unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        let stack = smaps.iter().find(|smap| smap.mapped_file.as_deref() == Some("[stack]"));
+        let stack = stack.unwrap();
+        assert_eq!(stack_pointers.len(), 1);
+        assert!(stack.from <= stack_pointers[0] && stack_pointers[0] < stack.to);
+    }
+}